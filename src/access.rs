@@ -0,0 +1,232 @@
+//! Access control of a room: the owner can lock it with a password or make it
+//! invite only.
+
+use std::collections::HashSet;
+
+use rand::Rng;
+
+use crate::admin::constant_time_eq;
+
+/// Proof of access given with `/join`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credential {
+    Password(String),
+    /// Token of an invite link
+    Invite(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lock {
+    Open,
+    Password(String),
+    InviteOnly,
+}
+
+/// The member who can lock the room, identified by the session id
+struct Owner {
+    id: usize,
+    name: String,
+}
+
+pub struct RoomAccess {
+    owner: Option<Owner>,
+    lock: Lock,
+    invites: HashSet<String>,
+}
+
+impl RoomAccess {
+    pub fn new() -> Self {
+        RoomAccess {
+            owner: None,
+            lock: Lock::Open,
+            invites: HashSet::new(),
+        }
+    }
+
+    pub fn is_owner(&self, id: usize) -> bool {
+        matches!(&self.owner, Some(owner) if owner.id == id)
+    }
+
+    /// The first member to join a room without owner becomes the owner
+    pub fn claim(&mut self, id: usize, name: &str) -> bool {
+        if self.owner.is_some() {
+            return false;
+        }
+        self.owner = Some(Owner {
+            id,
+            name: name.to_owned(),
+        });
+        true
+    }
+
+    /// The owner left, the next member to join becomes the owner
+    pub fn release(&mut self, id: usize) {
+        if self.is_owner(id) {
+            self.owner = None;
+        }
+    }
+
+    pub fn check(&self, id: usize, credential: Option<&Credential>) -> Result<(), String> {
+        if self.is_owner(id) {
+            return Ok(());
+        }
+        self.check_credential(credential)
+    }
+
+    /// Whether the credential gives access, i.e. for requests without a session
+    pub fn check_credential(&self, credential: Option<&Credential>) -> Result<(), String> {
+        if let Some(Credential::Invite(token)) = credential {
+            if self.is_valid_invite(token) {
+                return Ok(());
+            }
+        }
+        match (&self.lock, credential) {
+            (Lock::Open, _) => Ok(()),
+            (Lock::Password(password), Some(Credential::Password(given)))
+                if constant_time_eq(password.as_bytes(), given.as_bytes()) =>
+            {
+                Ok(())
+            }
+            (Lock::Password(_), _) => Err(
+                "the room is locked, a password is required (i.e. /join Room password:secret)"
+                    .to_string(),
+            ),
+            (Lock::InviteOnly, _) => {
+                Err("the room is invite only, ask the owner for an invite".to_string())
+            }
+        }
+    }
+
+    /// Locks with a password, or only for invites if there is none
+    pub fn lock(&mut self, password: Option<String>) {
+        self.lock = match password {
+            Some(password) => Lock::Password(password),
+            None => Lock::InviteOnly,
+        };
+    }
+
+    /// Opens the room and invalidates all invites
+    pub fn unlock(&mut self) {
+        self.lock = Lock::Open;
+        self.invites.clear();
+    }
+
+    pub fn create_invite(&mut self) -> String {
+        let token = format!("{:032x}", rand::thread_rng().gen::<u128>());
+        self.invites.insert(token.clone());
+        token
+    }
+
+    pub fn is_valid_invite(&self, token: &str) -> bool {
+        // all invites are compared, so that the time doesn't tell which matched
+        self.invites.iter().fold(false, |valid, invite| {
+            constant_time_eq(invite.as_bytes(), token.as_bytes()) | valid
+        })
+    }
+
+    pub fn describe(&self) -> String {
+        let owner = match self.owner.as_ref() {
+            Some(owner) => format!("Owner: '{}'", owner.name),
+            None => "The room has no owner".to_string(),
+        };
+        let lock = match self.lock {
+            Lock::Open => "open",
+            Lock::Password(_) => "locked with a password",
+            Lock::InviteOnly => "invite only",
+        };
+        format!("{}, the room is {}", owner, lock)
+    }
+}
+
+/// Whether a password of `/room lock` can be given to `/join`, which ends the
+/// password at the first space
+pub fn is_valid_password(password: &str) -> bool {
+    !password.is_empty() && !password.contains(char::is_whitespace)
+}
+
+/// Splits a trailing `password:...` or `invite:...` off the arguments of `/join`
+pub fn split_credential(input: &str) -> (&str, Option<Credential>) {
+    let input = input.trim();
+    if let Some((room, last)) = input.rsplit_once(' ') {
+        if let Some(password) = last.strip_prefix("password:") {
+            return (
+                room.trim_end(),
+                Some(Credential::Password(password.to_owned())),
+            );
+        }
+        if let Some(token) = last.strip_prefix("invite:") {
+            return (room.trim_end(), Some(Credential::Invite(token.to_owned())));
+        }
+    }
+    (input, None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lock_and_invites() {
+        let mut access = RoomAccess::new();
+        assert!(access.claim(1, "GM"));
+        assert!(!access.claim(2, "Thorin"));
+        assert!(access.check(2, None).is_ok());
+
+        access.lock(Some("secret".to_string()));
+        assert!(access.check(1, None).is_ok());
+        assert!(access.check(2, None).is_err());
+        assert!(access
+            .check(2, Some(&Credential::Password("wrong".to_string())))
+            .is_err());
+        assert!(access
+            .check(2, Some(&Credential::Password("secret".to_string())))
+            .is_ok());
+
+        access.lock(None);
+        let token = access.create_invite();
+        assert!(access
+            .check(2, Some(&Credential::Password("secret".to_string())))
+            .is_err());
+        assert!(access
+            .check(2, Some(&Credential::Invite(token.clone())))
+            .is_ok());
+        assert!(access.check_credential(None).is_err());
+        assert!(access
+            .check_credential(Some(&Credential::Invite(token.clone())))
+            .is_ok());
+
+        access.unlock();
+        assert!(!access.is_valid_invite(&token));
+
+        access.release(1);
+        assert!(access.claim(2, "Thorin"));
+    }
+
+    #[test]
+    fn test_split_credential() {
+        assert_eq!(("Main", None), split_credential("Main"));
+        assert_eq!(("The Kaer", None), split_credential("The Kaer"));
+        assert_eq!(
+            ("The Kaer", Some(Credential::Password("secret".to_string()))),
+            split_credential("The Kaer password:secret")
+        );
+        assert_eq!(
+            ("Main", Some(Credential::Invite("abc".to_string()))),
+            split_credential("Main invite:abc")
+        );
+    }
+
+    #[test]
+    fn test_lock_and_join() {
+        let mut access = RoomAccess::new();
+        access.claim(1, "GM");
+        assert!(is_valid_password("open-sesame"));
+        access.lock(Some("open-sesame".to_string()));
+        let (room, credential) = split_credential("The Kaer password:open-sesame");
+        assert_eq!("The Kaer", room);
+        assert!(access.check(2, credential.as_ref()).is_ok());
+
+        assert!(!is_valid_password("open sesame"));
+        assert!(!is_valid_password(""));
+    }
+}
@@ -0,0 +1,210 @@
+//! REST API to inspect and manage a running server, mounted at `/admin`.
+//! Every request needs the header `Authorization: Bearer <admin token>`, the
+//! API is disabled if no admin token is configured.
+
+use actix::Addr;
+use actix_web::error::{
+    ErrorForbidden, ErrorInternalServerError, ErrorNotFound, ErrorUnauthorized,
+};
+use actix_web::{http::header, web, Error, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::room::GetRoomMetrics;
+use crate::server::{
+    Broadcast, ChatServer, CloseRoom, GetServerState, Kick, ListSessions, SetWelcome,
+};
+use crate::PROBE_TIMEOUT;
+
+#[derive(Serialize)]
+struct RoomInfo {
+    name: String,
+    members: usize,
+    messages: u64,
+    rolls: u64,
+}
+
+#[derive(Deserialize)]
+struct TextBody {
+    message: String,
+}
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/rooms").route(web::get().to(list_rooms)))
+        .service(web::resource("/rooms/{name}").route(web::delete().to(close_room)))
+        .service(web::resource("/sessions").route(web::get().to(list_sessions)))
+        .service(web::resource("/sessions/{id}").route(web::delete().to(kick_session)))
+        .service(web::resource("/broadcast").route(web::post().to(broadcast)))
+        .service(web::resource("/welcome").route(web::put().to(set_welcome)));
+}
+
+/// Checks the bearer token of the request
+fn authorize(req: &HttpRequest, config: &Config) -> Result<(), Error> {
+    let expected = match config.admin_token.as_ref() {
+        Some(token) => token,
+        None => return Err(ErrorForbidden("the admin API is disabled")),
+    };
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Ok(()),
+        _ => {
+            warn!("Unauthorized admin request to {}", req.path());
+            Err(ErrorUnauthorized("invalid admin token"))
+        }
+    }
+}
+
+/// Compares without leaking the length of the common prefix
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn list_rooms(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    srv: web::Data<Addr<ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    authorize(&req, &config)?;
+    let state = srv
+        .send(GetServerState)
+        .timeout(PROBE_TIMEOUT)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    let mut rooms = vec![];
+    for room in state.rooms {
+        // rooms that don't answer in time are left out
+        if let Ok(metrics) = room.send(GetRoomMetrics).timeout(PROBE_TIMEOUT).await {
+            rooms.push(RoomInfo {
+                name: metrics.name,
+                members: metrics.members,
+                messages: metrics.messages,
+                rolls: metrics.rolls,
+            });
+        }
+    }
+    rooms.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(HttpResponse::Ok().json(rooms))
+}
+
+async fn close_room(
+    req: HttpRequest,
+    name: web::Path<String>,
+    config: web::Data<Config>,
+    srv: web::Data<Addr<ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    authorize(&req, &config)?;
+    let found = srv
+        .send(CloseRoom {
+            name: name.into_inner(),
+        })
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    if found {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(ErrorNotFound("no such room"))
+    }
+}
+
+async fn list_sessions(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    srv: web::Data<Addr<ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    authorize(&req, &config)?;
+    let sessions = srv
+        .send(ListSessions)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+async fn kick_session(
+    req: HttpRequest,
+    id: web::Path<usize>,
+    config: web::Data<Config>,
+    srv: web::Data<Addr<ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    authorize(&req, &config)?;
+    let found = srv
+        .send(Kick {
+            id: id.into_inner(),
+            reason: "You were disconnected by an admin".to_string(),
+        })
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    if found {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(ErrorNotFound("no such session"))
+    }
+}
+
+async fn broadcast(
+    req: HttpRequest,
+    body: web::Json<TextBody>,
+    config: web::Data<Config>,
+    srv: web::Data<Addr<ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    authorize(&req, &config)?;
+    srv.send(Broadcast(body.into_inner().message))
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn set_welcome(
+    req: HttpRequest,
+    body: web::Json<TextBody>,
+    config: web::Data<Config>,
+    srv: web::Data<Addr<ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    authorize(&req, &config)?;
+    srv.send(SetWelcome(body.into_inner().message))
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_authorize() {
+        let config = Config {
+            admin_token: Some("secret".to_string()),
+            ..Config::default()
+        };
+
+        let req = TestRequest::default()
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .to_http_request();
+        assert!(authorize(&req, &config).is_ok());
+
+        let req = TestRequest::default()
+            .header(header::AUTHORIZATION, "Bearer secreT")
+            .to_http_request();
+        assert!(authorize(&req, &config).is_err());
+
+        let req = TestRequest::default().to_http_request();
+        assert!(authorize(&req, &config).is_err());
+
+        let req = TestRequest::default()
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .to_http_request();
+        assert!(authorize(&req, &Config::default()).is_err());
+    }
+}
@@ -0,0 +1,84 @@
+/// Splits command arguments at whitespace. Arguments containing spaces can be
+/// wrapped in double quotes (i.e. `Thorin "Knocked Down" -3`).
+pub fn split_arguments(input: &str) -> Vec<String> {
+    let mut arguments = vec![];
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut has_argument = false;
+
+    for c in input.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                has_argument = true;
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if has_argument {
+                    arguments.push(std::mem::take(&mut current));
+                    has_argument = false;
+                }
+            }
+            c => {
+                current.push(c);
+                has_argument = true;
+            }
+        }
+    }
+    if has_argument {
+        arguments.push(current);
+    }
+
+    arguments
+}
+
+/// Splits off the first, possibly quoted, argument and leaves the rest as it
+/// is (i.e. `"Vizier Kratt" The gates are closed.`)
+pub fn split_first_argument(input: &str) -> Option<(String, &str)> {
+    let input = input.trim_start();
+    let (first, rest) = match input.strip_prefix('"') {
+        Some(quoted) => {
+            let end = quoted.find('"')?;
+            (&quoted[..end], &quoted[end + 1..])
+        }
+        None => match input.find(char::is_whitespace) {
+            Some(end) => (&input[..end], &input[end..]),
+            None => (input, ""),
+        },
+    };
+    if first.is_empty() {
+        return None;
+    }
+    Some((first.to_owned(), rest.trim()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split_arguments, split_first_argument};
+
+    #[test]
+    fn test_split_arguments() {
+        assert_eq!(
+            vec!["add", "Thorin", "Knocked Down", "-3", "rounds:1"],
+            split_arguments(r#"add Thorin "Knocked Down" -3 rounds:1"#)
+        );
+        assert_eq!(vec!["a", ""], split_arguments(r#"  a  "" "#));
+    }
+
+    #[test]
+    fn test_split_first_argument() {
+        assert_eq!(
+            Some(("Vizier Kratt".to_string(), "The gates  are closed.")),
+            split_first_argument(r#""Vizier Kratt" The gates  are closed."#)
+        );
+        assert_eq!(
+            Some(("Kratt".to_string(), "!![9] Intimidate")),
+            split_first_argument(" Kratt !![9] Intimidate")
+        );
+        assert_eq!(
+            Some(("Kratt".to_string(), "")),
+            split_first_argument("Kratt")
+        );
+        assert_eq!(None, split_first_argument(r#""Vizier Kratt"#));
+        assert_eq!(None, split_first_argument(""));
+    }
+}
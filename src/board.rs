@@ -0,0 +1,87 @@
+//! The topic, description and pinned messages of a room, i.e. the marching
+//! order, party loot or house rules.
+
+use serde::{Deserialize, Serialize};
+
+use crate::messages::TextMessageDTO;
+
+/// How many messages can be pinned in a room
+pub const MAX_PINS: usize = 20;
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Board {
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    /// Copies of the pinned messages, they stay when the history is cut
+    pins: Vec<TextMessageDTO>,
+}
+
+impl Board {
+    pub fn pins(&self) -> &[TextMessageDTO] {
+        &self.pins
+    }
+
+    pub fn is_pinned(&self, id: u64) -> bool {
+        self.pins.iter().any(|pin| pin.id() == Some(id))
+    }
+
+    pub fn pin(&mut self, message: TextMessageDTO) -> Result<(), String> {
+        let id = match message.id() {
+            Some(id) => id,
+            None => return Err("the message can't be pinned".to_string()),
+        };
+        if self.is_pinned(id) {
+            return Err("the message is already pinned".to_string());
+        }
+        if self.pins.len() >= MAX_PINS {
+            return Err(format!("a room can have at most {} pins", MAX_PINS));
+        }
+        self.pins.push(message);
+        Ok(())
+    }
+
+    pub fn unpin(&mut self, id: u64) -> bool {
+        let count = self.pins.len();
+        self.pins.retain(|pin| pin.id() != Some(id));
+        self.pins.len() != count
+    }
+
+    /// Replaces a pinned message after it was edited, returns whether it is
+    /// pinned
+    pub fn update(&mut self, message: &TextMessageDTO) -> bool {
+        match self.pins.iter_mut().find(|pin| pin.id() == message.id()) {
+            Some(pin) => {
+                *pin = message.clone();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pins() {
+        let mut board = Board::default();
+        let loot = TextMessageDTO::chat("Loot: 30 silver", "Thorin").with_id(3);
+        assert!(board
+            .pin(TextMessageDTO::chat("not kept", "Thorin"))
+            .is_err());
+        assert!(board.pin(loot.clone()).is_ok());
+        assert!(board.pin(loot.clone()).is_err());
+        assert!(board.is_pinned(3));
+
+        let mut edited = loot;
+        edited.edit("Loot: 30 silver, a dagger");
+        assert!(board.update(&edited));
+        assert_eq!("Loot: 30 silver, a dagger", board.pins()[0].message());
+        assert!(!board.update(&TextMessageDTO::chat("Hi", "Gimli").with_id(4)));
+
+        assert!(board.unpin(3));
+        assert!(!board.unpin(3));
+        assert!(board.pins().is_empty());
+    }
+}
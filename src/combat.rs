@@ -0,0 +1,156 @@
+//! Combat round tracking for a `ChatRoom`.
+//!
+//! While a combat is running, initiative rolls (i.e. `!!1d6 (ini) attack` or
+//! `!!2d6 (ini:Boss) kill everyone`) are collected into the initiative order.
+//! Initiative is rolled anew every round, so starting a new round clears the
+//! order together with the active combatant.
+
+use crate::messages::{CombatStateDTO, InitiativeDTO};
+
+pub struct Combat {
+    round: u32,
+    order: Vec<InitiativeDTO>,
+    /// Key (name, sub name) of the combatant whose turn it is
+    active: Option<(String, Option<String>)>,
+}
+
+/// Result of advancing to the next combatant
+pub enum Turn<'a> {
+    Actor(&'a InitiativeDTO),
+    EndOfRound,
+    NoInitiatives,
+}
+
+impl Combat {
+    pub fn new() -> Self {
+        Combat {
+            round: 1,
+            order: vec![],
+            active: None,
+        }
+    }
+
+    pub fn round(&self) -> u32 {
+        self.round
+    }
+
+    /// Adds an initiative to the order, replacing a previous roll of the same
+    /// combatant.
+    pub fn add_initiative(&mut self, initiative: InitiativeDTO) {
+        self.order
+            .retain(|i| !(i.name == initiative.name && i.sub_name == initiative.sub_name));
+        let position = self
+            .order
+            .iter()
+            .position(|i| i.result < initiative.result)
+            .unwrap_or(self.order.len());
+        self.order.insert(position, initiative);
+    }
+
+    pub fn next_turn(&mut self) -> Turn<'_> {
+        if self.order.is_empty() {
+            return Turn::NoInitiatives;
+        }
+
+        let next = match self.active_index() {
+            Some(index) => index + 1,
+            None => 0,
+        };
+
+        match self.order.get(next) {
+            Some(actor) => {
+                self.active = Some((actor.name.clone(), actor.sub_name.clone()));
+                Turn::Actor(actor)
+            }
+            None => {
+                self.active = None;
+                Turn::EndOfRound
+            }
+        }
+    }
+
+    pub fn next_round(&mut self) {
+        self.round += 1;
+        self.order.clear();
+        self.active = None;
+    }
+
+    fn active_index(&self) -> Option<usize> {
+        let (name, sub_name) = self.active.as_ref()?;
+        self.order
+            .iter()
+            .position(|i| &i.name == name && &i.sub_name == sub_name)
+    }
+
+    pub fn to_dto(&self) -> CombatStateDTO {
+        CombatStateDTO {
+            in_combat: true,
+            round: self.round,
+            active: self.active_index(),
+            order: self.order.clone(),
+        }
+    }
+}
+
+/// Extracts the initiative sub name and the description from a roll message
+/// like `!!1d6 (ini:Minion) attack`. The outer option is `None` if the message
+/// is no initiative roll.
+pub fn parse_initiative(message: &str) -> Option<(Option<String>, String)> {
+    if !message.starts_with('!') {
+        return None;
+    }
+
+    let (_, tag) = message.split_once("(ini")?;
+    let (sub_name, description) = if let Some(rest) = tag.strip_prefix(')') {
+        (None, rest)
+    } else {
+        let (sub_name, rest) = tag.strip_prefix(':')?.split_once(')')?;
+        (Some(sub_name.to_owned()), rest)
+    };
+
+    Some((sub_name, description.trim().to_owned()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn initiative(name: &str, result: i32) -> InitiativeDTO {
+        InitiativeDTO {
+            name: name.to_owned(),
+            sub_name: None,
+            result,
+            description: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse_initiative() {
+        assert_eq!(
+            Some((None, "attack".to_owned())),
+            parse_initiative("!!1d6 (ini) attack")
+        );
+        assert_eq!(
+            Some((Some("Boss".to_owned()), "kill everyone".to_owned())),
+            parse_initiative("!!2d6 (ini:Boss) kill everyone")
+        );
+        assert_eq!(None, parse_initiative("!!1d6 attack"));
+        assert_eq!(None, parse_initiative("(ini) attack"));
+    }
+
+    #[test]
+    fn test_turn_order() {
+        let mut combat = Combat::new();
+        combat.add_initiative(initiative("Thorin", 7));
+        combat.add_initiative(initiative("Goblin", 12));
+        combat.add_initiative(initiative("Thorin", 3));
+
+        assert!(matches!(combat.next_turn(), Turn::Actor(a) if a.name == "Goblin"));
+        assert!(matches!(combat.next_turn(), Turn::Actor(a) if a.name == "Thorin"));
+        assert!(matches!(combat.next_turn(), Turn::EndOfRound));
+
+        combat.next_round();
+        assert_eq!(2, combat.round());
+        assert!(matches!(combat.next_turn(), Turn::NoInitiatives));
+    }
+}
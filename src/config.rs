@@ -0,0 +1,418 @@
+//! Server configuration. Values are taken from (in increasing priority) the
+//! defaults, a TOML file given with `--config`, environment variables
+//! (`EDDRC_*`) and command line flags.
+
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::dice::DiceLimits;
+use crate::history::DEFAULT_HISTORY_SIZE;
+use crate::ratelimit::RateLimits;
+
+#[derive(Parser, Debug)]
+#[clap(version, about = "Earthdawn dice rolling chat server")]
+pub struct Cli {
+    /// Port to listen on (same as --port)
+    #[clap(value_name = "PORT")]
+    legacy_port: Option<u16>,
+    /// TOML configuration file
+    #[clap(short, long, env = "EDDRC_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address to bind to, i.e. 0.0.0.0 for all interfaces
+    #[clap(long, env = "EDDRC_BIND")]
+    bind: Option<String>,
+    /// Port to listen on
+    #[clap(short, long, env = "EDDRC_PORT")]
+    port: Option<u16>,
+    /// Directory of the web client
+    #[clap(long, env = "EDDRC_STATIC_DIR")]
+    static_dir: Option<PathBuf>,
+    /// Seconds between heartbeat pings
+    #[clap(long, env = "EDDRC_HEARTBEAT_INTERVAL")]
+    heartbeat_interval: Option<u64>,
+    /// Seconds without an answer after which a client is disconnected
+    #[clap(long, env = "EDDRC_CLIENT_TIMEOUT")]
+    client_timeout: Option<u64>,
+    /// Rooms created on startup, can be repeated
+    #[clap(
+        long = "default-room",
+        env = "EDDRC_DEFAULT_ROOMS",
+        value_delimiter = ','
+    )]
+    default_rooms: Vec<String>,
+    /// Number of messages kept per room
+    #[clap(long, env = "EDDRC_HISTORY_SIZE")]
+    history_size: Option<usize>,
+    /// Directory to store the room state in, nothing is stored if not set
+    #[clap(long, env = "EDDRC_STORAGE_DIR")]
+    storage_dir: Option<PathBuf>,
+    /// Log level (error, warn, info, debug or trace), RUST_LOG takes precedence
+    #[clap(long, env = "EDDRC_LOG_LEVEL")]
+    log_level: Option<String>,
+    /// PEM encoded certificate chain, enables https
+    #[clap(long, env = "EDDRC_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+    /// PEM encoded private key of the certificate
+    #[clap(long, env = "EDDRC_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+    /// Port of a plain HTTP listener which redirects to https
+    #[clap(long, env = "EDDRC_HTTP_REDIRECT_PORT")]
+    http_redirect_port: Option<u16>,
+    /// Reverse proxy whose `X-Forwarded-For` header is trusted, can be repeated
+    #[clap(
+        long = "trusted-proxy",
+        env = "EDDRC_TRUSTED_PROXIES",
+        value_delimiter = ','
+    )]
+    trusted_proxies: Vec<IpAddr>,
+    /// Seconds until the server is expected back after a shutdown, told to the clients
+    #[clap(long, env = "EDDRC_SHUTDOWN_ETA")]
+    shutdown_eta: Option<u64>,
+    /// Seconds a disconnected session can be resumed with its resume token
+    #[clap(long, env = "EDDRC_RESUME_GRACE_PERIOD")]
+    resume_grace_period: Option<u64>,
+    /// Seconds without any message until a session is shown as idle
+    #[clap(long, env = "EDDRC_IDLE_TIMEOUT")]
+    idle_timeout: Option<u64>,
+    /// Token to authenticate as admin, admin commands are disabled if not set
+    #[clap(long, env = "EDDRC_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+    /// Maximum length of a websocket message in bytes
+    #[clap(long, env = "EDDRC_MAX_MESSAGE_LENGTH")]
+    max_message_length: Option<usize>,
+    /// Maximum number of dice rolled by a single message
+    #[clap(long, env = "EDDRC_MAX_DICE")]
+    max_dice: Option<u32>,
+    /// Maximum number of sides of a die
+    #[clap(long, env = "EDDRC_MAX_DIE_SIDES")]
+    max_die_sides: Option<u32>,
+    /// Maximum number of times a single die explodes
+    #[clap(long, env = "EDDRC_MAX_EXPLOSIONS")]
+    max_explosions: Option<u32>,
+    /// Maximum number of terms of a roll
+    #[clap(long, env = "EDDRC_MAX_TERMS")]
+    max_terms: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: String,
+    pub port: u16,
+    pub static_dir: PathBuf,
+    /// Seconds between heartbeat pings
+    pub heartbeat_interval: u64,
+    /// Seconds without an answer after which a client is disconnected
+    pub client_timeout: u64,
+    pub default_rooms: Vec<String>,
+    pub history_size: usize,
+    pub storage_dir: Option<PathBuf>,
+    pub log_level: String,
+    /// PEM encoded certificate chain, enables https together with `tls_key`
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Port of a plain HTTP listener which redirects to https
+    pub http_redirect_port: Option<u16>,
+    /// Reverse proxies whose `X-Forwarded-For` header is trusted, the
+    /// address of the peer is used otherwise
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Seconds until the server is expected back after a shutdown
+    pub shutdown_eta: Option<u64>,
+    /// Seconds a disconnected session can be resumed with its resume token
+    pub resume_grace_period: u64,
+    /// Seconds without any message until a session is shown as idle
+    pub idle_timeout: u64,
+    /// Token to authenticate as admin, admin commands are disabled if not set
+    pub admin_token: Option<String>,
+    /// Maximum length of a websocket message in bytes
+    pub max_message_length: usize,
+    /// Limits of the dice expressions, `max_dice` is also the limit for
+    /// all repetitions of a roll together
+    pub dice_limits: DiceLimits,
+    /// Only configurable in the configuration file
+    pub rate_limits: RateLimits,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: "127.0.0.1".to_string(),
+            port: 8080,
+            static_dir: PathBuf::from("static/"),
+            heartbeat_interval: 5,
+            client_timeout: 10,
+            default_rooms: vec!["Main".to_string()],
+            history_size: DEFAULT_HISTORY_SIZE,
+            storage_dir: None,
+            log_level: "info".to_string(),
+            tls_cert: None,
+            tls_key: None,
+            http_redirect_port: None,
+            trusted_proxies: vec![],
+            shutdown_eta: None,
+            resume_grace_period: 60,
+            idle_timeout: 300,
+            admin_token: None,
+            max_message_length: 2000,
+            dice_limits: DiceLimits::default(),
+            rate_limits: RateLimits::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the configuration file given on the command line (if any) and
+    /// applies the overrides of the command line and environment
+    pub fn load(cli: Cli) -> Result<Self, String> {
+        let config = match cli.config.as_ref() {
+            Some(path) => {
+                let content = fs::read_to_string(path)
+                    .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+                toml::from_str(&content)
+                    .map_err(|e| format!("invalid configuration {}: {}", path.display(), e))?
+            }
+            None => Config::default(),
+        };
+
+        config.with_overrides(cli)
+    }
+
+    fn with_overrides(mut self, cli: Cli) -> Result<Self, String> {
+        if let Some(bind) = cli.bind {
+            self.bind = bind;
+        }
+        if let Some(port) = cli.port.or(cli.legacy_port) {
+            self.port = port;
+        }
+        if let Some(static_dir) = cli.static_dir {
+            self.static_dir = static_dir;
+        }
+        if let Some(heartbeat_interval) = cli.heartbeat_interval {
+            self.heartbeat_interval = heartbeat_interval;
+        }
+        if let Some(client_timeout) = cli.client_timeout {
+            self.client_timeout = client_timeout;
+        }
+        if !cli.default_rooms.is_empty() {
+            self.default_rooms = cli.default_rooms;
+        }
+        if let Some(history_size) = cli.history_size {
+            self.history_size = history_size;
+        }
+        if cli.storage_dir.is_some() {
+            self.storage_dir = cli.storage_dir;
+        }
+        if let Some(log_level) = cli.log_level {
+            self.log_level = log_level;
+        }
+        if cli.tls_cert.is_some() {
+            self.tls_cert = cli.tls_cert;
+        }
+        if cli.tls_key.is_some() {
+            self.tls_key = cli.tls_key;
+        }
+        if cli.http_redirect_port.is_some() {
+            self.http_redirect_port = cli.http_redirect_port;
+        }
+        if !cli.trusted_proxies.is_empty() {
+            self.trusted_proxies = cli.trusted_proxies;
+        }
+        if cli.shutdown_eta.is_some() {
+            self.shutdown_eta = cli.shutdown_eta;
+        }
+        if let Some(resume_grace_period) = cli.resume_grace_period {
+            self.resume_grace_period = resume_grace_period;
+        }
+        if let Some(idle_timeout) = cli.idle_timeout {
+            self.idle_timeout = idle_timeout;
+        }
+        if cli.admin_token.is_some() {
+            self.admin_token = cli.admin_token;
+        }
+        if let Some(max_message_length) = cli.max_message_length {
+            self.max_message_length = max_message_length;
+        }
+        if let Some(max_dice) = cli.max_dice {
+            self.dice_limits.max_dice = max_dice;
+        }
+        if let Some(max_die_sides) = cli.max_die_sides {
+            self.dice_limits.max_sides = max_die_sides;
+        }
+        if let Some(max_explosions) = cli.max_explosions {
+            self.dice_limits.max_explosions = max_explosions;
+        }
+        if let Some(max_terms) = cli.max_terms {
+            self.dice_limits.max_terms = max_terms;
+        }
+
+        if self.heartbeat_interval == 0 || self.client_timeout <= self.heartbeat_interval {
+            return Err(
+                "the client timeout must be longer than the heartbeat interval".to_string(),
+            );
+        }
+        if self.idle_timeout == 0 {
+            return Err("the idle timeout must be at least 1 second".to_string());
+        }
+        if self.history_size == 0 {
+            return Err("the history size must be at least 1".to_string());
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err("https needs both a certificate and a private key".to_string());
+        }
+        if self.http_redirect_port.is_some() && self.tls_cert.is_none() {
+            return Err("redirecting to https needs a certificate and a private key".to_string());
+        }
+        if self.max_message_length == 0 {
+            return Err("the maximum message length must be at least 1".to_string());
+        }
+        let limits = &self.dice_limits;
+        if limits.max_dice == 0 || limits.max_sides == 0 || limits.max_terms == 0 {
+            return Err("the dice limits must be at least 1".to_string());
+        }
+        self.rate_limits.validate()?;
+
+        Ok(self)
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval)
+    }
+
+    pub fn client_timeout(&self) -> Duration {
+        Duration::from_secs(self.client_timeout)
+    }
+
+    pub fn resume_grace_period(&self) -> Duration {
+        Duration::from_secs(self.resume_grace_period)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout)
+    }
+
+    /// The address of a client, as forwarded by a trusted proxy or else the
+    /// address of the peer. Proxies append to `X-Forwarded-For`, so only the
+    /// last entry is from the proxy, the ones before are from the client.
+    /// Loopback and private addresses are `None`, they are likely shared by
+    /// many clients (i.e. an untrusted proxy) and must not be banned together.
+    pub fn client_addr(&self, peer: Option<IpAddr>, forwarded_for: Option<&str>) -> Option<IpAddr> {
+        let peer = peer?;
+        let addr = if self.trusted_proxies.contains(&peer) {
+            forwarded_for
+                .and_then(|header| header.rsplit(',').next())
+                .and_then(|addr| addr.trim().parse().ok())
+                .unwrap_or(peer)
+        } else {
+            peer
+        };
+        Some(addr).filter(|addr| !is_shared(addr))
+    }
+}
+
+/// Whether the address is loopback, private or link local
+fn is_shared(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => addr.is_loopback() || addr.is_private() || addr.is_link_local(),
+        IpAddr::V6(addr) => {
+            addr.is_loopback() || addr.is_unique_local() || addr.is_unicast_link_local()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::moderation::Moderation;
+    use std::time::Instant;
+
+    #[test]
+    fn test_file_and_overrides() {
+        let config: Config = toml::from_str(
+            r#"
+            bind = "0.0.0.0"
+            port = 9000
+            default_rooms = ["Main", "Barsaive"]
+
+            [rate_limits.session]
+            rolls = { rate = 1.0, burst = 5 }
+            "#,
+        )
+        .unwrap();
+        assert_eq!("0.0.0.0", config.bind);
+        assert_eq!(10, config.client_timeout);
+        assert_eq!(5, config.rate_limits.session.rolls.burst);
+        assert_eq!(10, config.rate_limits.session.chat.burst);
+
+        let cli = Cli::parse_from(["eddrc", "7000", "--history-size", "50"]);
+        let config = config.with_overrides(cli).unwrap();
+        assert_eq!(7000, config.port);
+        assert_eq!(50, config.history_size);
+        assert_eq!(vec!["Main", "Barsaive"], config.default_rooms);
+
+        let cli = Cli::parse_from(["eddrc", "7000", "--port", "7001"]);
+        assert_eq!(7001, Config::default().with_overrides(cli).unwrap().port);
+    }
+
+    #[test]
+    fn test_client_addr() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        let config = Config {
+            trusted_proxies: vec![proxy],
+            ..Config::default()
+        };
+        let spoofed = Some("198.51.100.1, 203.0.113.7");
+        assert_eq!(Some(client), config.client_addr(Some(proxy), spoofed));
+        assert_eq!(None, config.client_addr(Some(proxy), None));
+        assert_eq!(None, config.client_addr(Some(proxy), Some("192.168.1.20")));
+        assert_eq!(Some(client), config.client_addr(Some(client), spoofed));
+        assert_eq!(
+            Some(client),
+            Config::default().client_addr(Some(client), Some("198.51.100.1"))
+        );
+    }
+
+    #[test]
+    fn test_shared_peer_addr() {
+        // two members behind a proxy which isn't trusted share its address
+        let proxy = Some("127.0.0.1".parse().unwrap());
+        let config = Config::default();
+        let thorin = config.client_addr(proxy, Some("203.0.113.7"));
+        let troll = config.client_addr(proxy, Some("203.0.113.8"));
+        assert_eq!(None, thorin);
+
+        // banning the troll by address leaves Thorin alone
+        let mut moderation = Moderation::default();
+        let addrs: Vec<String> = troll.iter().map(|addr| addr.to_string()).collect();
+        let now = Instant::now();
+        moderation.ban("Troll", &addrs, None, now).unwrap();
+        let thorin = thorin.map(|addr| addr.to_string());
+        assert!(!moderation.is_banned("Thorin", thorin.as_deref(), now));
+        assert!(moderation.is_banned("Troll", None, now));
+
+        let peer = Some("::1".parse().unwrap());
+        assert_eq!(None, config.client_addr(peer, None));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(toml::from_str::<Config>("prot = 9000").is_err());
+
+        let cli = Cli::parse_from(["eddrc", "--heartbeat-interval", "20"]);
+        assert!(Config::default().with_overrides(cli).is_err());
+
+        let cli = Cli::parse_from(["eddrc", "--tls-cert", "cert.pem"]);
+        assert!(Config::default().with_overrides(cli).is_err());
+
+        let cli = Cli::parse_from(["eddrc", "--http-redirect-port", "80"]);
+        assert!(Config::default().with_overrides(cli).is_err());
+
+        let cli = Cli::parse_from(["eddrc", "--idle-timeout", "0"]);
+        assert!(Config::default().with_overrides(cli).is_err());
+    }
+}
@@ -1,8 +1,211 @@
 use std::collections::VecDeque;
+use std::convert::TryFrom;
 
 use rand::prelude::*;
+use serde::Deserialize;
 
-pub fn get_results(request: &str) -> Vec<i32> {
+use crate::messages::{DieResultDTO, TermResultDTO};
+
+/// Earthdawn step/action dice table, starting at step 1
+const STEP_ACTION_DICE: [&str; 30] = [
+    "1d4-2",
+    "1d4-1",
+    "1d4",
+    "1d6",
+    "1d8",
+    "1d10",
+    "1d12",
+    "2d6",
+    "1d8+1d6",
+    "1d10+1d6",
+    "1d10+1d8",
+    "2d10",
+    "1d12+1d10",
+    "2d12",
+    "1d20+1d6",
+    "1d20+1d8",
+    "1d20+1d10",
+    "1d20+1d12",
+    "1d20+2d6",
+    "1d20+1d8+1d6",
+    "1d20+1d10+1d6",
+    "1d20+1d10+1d8",
+    "1d20+2d10",
+    "1d20+1d12+1d10",
+    "1d20+1d10+1d8+1d4",
+    "1d20+1d10+1d8+1d6",
+    "1d20+1d10+2d8",
+    "1d20+2d10+1d8",
+    "1d20+1d12+1d10+1d8",
+    "1d20+1d10+1d8+2d6",
+];
+
+/// Upper limit for rolling an expression multiple times (i.e. `!5x[8]`)
+pub const MAX_REPETITIONS: u32 = 20;
+
+/// Limits for parsed expressions, so that a roll can't keep the room busy or
+/// overflow its result
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiceLimits {
+    /// Dice of all terms of an expression together
+    pub max_dice: u32,
+    pub max_sides: u32,
+    /// Times a single die can explode
+    pub max_explosions: u32,
+    pub max_terms: usize,
+}
+
+impl Default for DiceLimits {
+    fn default() -> Self {
+        DiceLimits {
+            max_dice: 100,
+            max_sides: 1000,
+            max_explosions: 100,
+            max_terms: 20,
+        }
+    }
+}
+
+/// A parsed dice expression that can be rolled multiple times
+pub struct DiceExpression {
+    pub terms: Vec<Term>,
+}
+
+pub struct Term {
+    /// The term as written, i.e. `-4d6kh3`
+    pub expression: String,
+    pub negative: bool,
+    pub kind: TermKind,
+}
+
+pub enum TermKind {
+    Dice(DiceTerm),
+    Number(i32),
+}
+
+/// Dice like `4d6kh3`, `2d6!`, `d6r1` or `d10!>9`
+pub struct DiceTerm {
+    pub count: u32,
+    pub sides: u32,
+    /// Dice showing at least this value are rolled again and added
+    pub explode_at: Option<u32>,
+    /// Dice showing at most this value are rerolled once
+    pub reroll_at_most: Option<u32>,
+    pub keep: Option<Keep>,
+    /// Times a single die can explode
+    pub max_explosions: u32,
+}
+
+#[derive(Clone, Copy)]
+pub enum Keep {
+    Highest(u32),
+    Lowest(u32),
+}
+
+impl DiceExpression {
+    /// Number of dice, without explosions and rerolls
+    pub fn dice_count(&self) -> u64 {
+        self.terms
+            .iter()
+            .map(|term| match &term.kind {
+                TermKind::Dice(dice) => u64::from(dice.count),
+                TermKind::Number(_) => 0,
+            })
+            .sum()
+    }
+
+    pub fn roll(&self) -> Vec<TermResultDTO> {
+        let mut rng = thread_rng();
+        self.terms.iter().map(|term| term.roll(&mut rng)).collect()
+    }
+}
+
+impl Term {
+    fn roll(&self, rng: &mut ThreadRng) -> TermResultDTO {
+        let (total, dice) = match &self.kind {
+            TermKind::Dice(dice_term) => {
+                let dice = dice_term.roll(rng);
+                let total = dice
+                    .iter()
+                    .filter(|d| !d.dropped)
+                    .flat_map(|d| d.rolls.iter())
+                    .fold(0u64, |sum, roll| sum.saturating_add(u64::from(*roll)));
+                // the parser rejects expressions which could overflow
+                let total = i32::try_from(total).unwrap_or(i32::MAX);
+                (total, dice)
+            }
+            TermKind::Number(i) => (*i, vec![]),
+        };
+
+        TermResultDTO {
+            expression: self.expression.clone(),
+            total: if self.negative { -total } else { total },
+            dice,
+        }
+    }
+}
+
+impl DiceTerm {
+    fn roll(&self, rng: &mut ThreadRng) -> Vec<DieResultDTO> {
+        if self.sides < 1 {
+            return vec![];
+        }
+
+        let mut dice: Vec<DieResultDTO> = (0..self.count).map(|_| self.roll_die(rng)).collect();
+
+        if let Some(keep) = self.keep {
+            let mut order: Vec<usize> = (0..dice.len()).collect();
+            order.sort_by_key(|&i| dice[i].rolls.iter().sum::<u32>());
+            let kept = match keep {
+                Keep::Highest(n) => order.split_off(order.len().saturating_sub(n as usize)),
+                Keep::Lowest(n) => order.drain(..(n as usize).min(order.len())).collect(),
+            };
+            for (i, die) in dice.iter_mut().enumerate() {
+                die.dropped = !kept.contains(&i);
+            }
+        }
+
+        dice
+    }
+
+    fn roll_die(&self, rng: &mut ThreadRng) -> DieResultDTO {
+        let mut face = rng.gen_range(1, self.sides + 1);
+        let mut rerolled = None;
+        if matches!(self.reroll_at_most, Some(r) if face <= r) {
+            rerolled = Some(face);
+            face = rng.gen_range(1, self.sides + 1);
+        }
+
+        let mut rolls = vec![face];
+        if let Some(threshold) = self.explode_at {
+            while face >= threshold && rolls.len() <= self.max_explosions as usize {
+                face = rng.gen_range(1, self.sides + 1);
+                rolls.push(face);
+            }
+        }
+
+        DieResultDTO {
+            sides: self.sides,
+            rolls,
+            rerolled,
+            dropped: false,
+        }
+    }
+}
+
+/// Sums of the single terms of a roll, as shown to the users
+pub fn totals(results: &[TermResultDTO]) -> Vec<i32> {
+    if results.is_empty() {
+        vec![-666]
+    } else {
+        results.iter().map(|t| t.total).collect()
+    }
+}
+
+/// Parses the roll part of a request, i.e. `2d6+d8!+3`. Unknown terms are
+/// skipped, expressions exceeding the limits are rejected.
+pub fn parse(request: &str, limits: &DiceLimits) -> Result<DiceExpression, String> {
     let plus_minus: &[char] = &['+', '-'][..];
 
     let request = strip_text(request);
@@ -19,76 +222,257 @@ pub fn get_results(request: &str) -> Vec<i32> {
         separators.push_front("+");
     }
 
-    let dice_result: Vec<i32> = s
-        .split(plus_minus)
-        .zip(separators)
-        .map(|(part, sign)| run(&format!("{}{}", sign, part), should_explode))
-        .filter_map(|x| x)
-        .collect();
-
-    if dice_result.len() == 0 {
-        vec![-666]
-    } else {
-        dice_result
+    let mut terms = vec![];
+    for (part, sign) in s.split(plus_minus).zip(separators) {
+        if let Some(term) = parse_term(&format!("{}{}", sign, part), should_explode, limits)? {
+            terms.push(term);
+        }
     }
+
+    let expression = DiceExpression { terms };
+    check_limits(&expression, limits)?;
+    Ok(expression)
 }
 
-fn run(input: &str, should_explode: bool) -> Option<i32> {
-    if input.contains('d') {
-        let negative = input.starts_with('-');
+fn check_limits(expression: &DiceExpression, limits: &DiceLimits) -> Result<(), String> {
+    if expression.terms.len() > limits.max_terms {
+        return Err(format!(
+            "a roll can have at most {} terms",
+            limits.max_terms
+        ));
+    }
+    if expression.dice_count() > u64::from(limits.max_dice) {
+        return Err(format!("a roll can have at most {} dice", limits.max_dice));
+    }
 
-        let input = if negative { &input[1..] } else { &input[..] };
+    // the largest possible result must fit into the total of a roll
+    let mut maximum: u64 = 0;
+    for term in expression.terms.iter() {
+        let term_maximum = match &term.kind {
+            TermKind::Dice(dice) => {
+                if dice.sides > limits.max_sides {
+                    return Err(format!("dice can have at most {} sides", limits.max_sides));
+                }
+                // most dice would explode again and again up to the limit
+                if let Some(threshold) = dice.explode_at {
+                    if 2 * (dice.sides.saturating_sub(threshold) + 1) > dice.sides {
+                        return Err("at most half of the faces of a die can explode".to_string());
+                    }
+                }
+                let rolls_per_die = match dice.explode_at {
+                    Some(_) => u64::from(dice.max_explosions) + 1,
+                    None => 1,
+                };
+                u64::from(dice.count)
+                    .checked_mul(u64::from(dice.sides))
+                    .and_then(|m| m.checked_mul(rolls_per_die))
+            }
+            TermKind::Number(i) => Some(u64::from(i.unsigned_abs())),
+        };
+        maximum = match term_maximum.and_then(|m| maximum.checked_add(m)) {
+            Some(maximum) => maximum,
+            None => return Err("the result of the roll would be too large".to_string()),
+        };
+    }
+    if maximum > i32::MAX as u64 {
+        return Err("the result of the roll would be too large".to_string());
+    }
 
-        let parts: Vec<&str> = input.split('d').collect();
-        let a = if parts[0] == "+" || parts[0] == "-" {
+    Ok(())
+}
+
+/// Parses a single term with its sign, `None` if it is not a valid term.
+/// Numbers which are too large are an error, to not silently skip them.
+fn parse_term(
+    input: &str,
+    should_explode: bool,
+    limits: &DiceLimits,
+) -> Result<Option<Term>, String> {
+    let negative = input.starts_with('-');
+    let expression = if negative { input } else { &input[1..] };
+
+    let kind = if input.contains('d') {
+        let input = &input[1..];
+        let (count, rest) = match input.find('d') {
+            Some(i) => input.split_at(i),
+            None => return Ok(None),
+        };
+        let count = if count.is_empty() {
             1
         } else {
-            if let Ok(i) = parts[0].parse::<u32>() {
-                i
-            } else {
-                return None;
+            match parse_number::<u32>(count)? {
+                Some(count) => count,
+                None => return Ok(None),
             }
         };
-        let b = if let Ok(i) = parts[1].parse::<u32>() {
-            i
-        } else {
-            return None;
+
+        let rest = &rest[1..];
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        let sides = match parse_number::<u32>(&rest[..digits])? {
+            Some(sides) => sides,
+            None => return Ok(None),
         };
 
-        let mut result = roll(a, b, should_explode);
-        if negative {
-            result = -result;
+        let mut dice_term = DiceTerm {
+            count,
+            sides,
+            explode_at: None,
+            reroll_at_most: None,
+            keep: None,
+            max_explosions: limits.max_explosions,
+        };
+        if should_explode {
+            dice_term.explode_at = Some(sides);
+        }
+        if parse_modifiers(&rest[digits..], &mut dice_term).is_none() {
+            return Ok(None);
+        }
+        if sides <= 1 || matches!(dice_term.explode_at, Some(t) if t <= 1) {
+            // such dice would explode forever
+            dice_term.explode_at = None;
         }
 
-        return Some(result);
+        TermKind::Dice(dice_term)
+    } else {
+        match parse_number::<i32>(&input[1..])? {
+            Some(number) => TermKind::Number(number),
+            None => return Ok(None),
+        }
+    };
+
+    Ok(Some(Term {
+        expression: expression.to_owned(),
+        negative,
+        kind,
+    }))
+}
+
+/// `None` if the input is not a number, an error if it is too large
+fn parse_number<T: std::str::FromStr>(input: &str) -> Result<Option<T>, String> {
+    match input.parse::<T>() {
+        Ok(number) => Ok(Some(number)),
+        Err(_) if !input.is_empty() && input.chars().all(|c| c.is_ascii_digit()) => {
+            Err(format!("the number {} is too large", input))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Parses modifiers like `kh3`, `kl1`, `dl1`, `dh1`, `r1`, `!` and `!>9`
+fn parse_modifiers(mut input: &str, dice_term: &mut DiceTerm) -> Option<()> {
+    fn number(input: &str) -> Option<(u32, &str)> {
+        let digits = input.chars().take_while(|c| c.is_ascii_digit()).count();
+        Some((input[..digits].parse().ok()?, &input[digits..]))
     }
 
-    if let Ok(i) = input.parse::<i32>() {
-        Some(i)
+    while !input.is_empty() {
+        if let Some(rest) = input.strip_prefix("!>") {
+            let (threshold, rest) = number(rest)?;
+            dice_term.explode_at = Some(threshold);
+            input = rest;
+        } else if let Some(rest) = input.strip_prefix('!') {
+            dice_term.explode_at = Some(dice_term.sides);
+            input = rest;
+        } else if let Some(rest) = input.strip_prefix('r') {
+            let (value, rest) = number(rest)?;
+            dice_term.reroll_at_most = Some(value);
+            input = rest;
+        } else {
+            let modifier = input.get(..2)?;
+            let (n, rest) = number(&input[2..])?;
+            let count = dice_term.count;
+            dice_term.keep = Some(match modifier {
+                "kh" => Keep::Highest(n),
+                "kl" => Keep::Lowest(n),
+                "dl" => Keep::Highest(count.saturating_sub(n)),
+                "dh" => Keep::Lowest(count.saturating_sub(n)),
+                _ => return None,
+            });
+            input = rest;
+        }
+    }
+
+    Some(())
+}
+
+/// Splits a repetition prefix like `5x` off a roll request, i.e. `!5x[8] Spear`
+/// is rolled 5 times as `![8] Spear` and `!!3x2d6` 3 times as `!!2d6`.
+pub fn split_repetitions(request: &str) -> (u32, String) {
+    let (marker, rest) = if request.starts_with("!!") {
+        request.split_at(2)
     } else {
-        None
+        request.split_at(1)
+    };
+
+    let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+    match (
+        rest[..digits].parse::<u32>(),
+        rest[digits..].strip_prefix('x'),
+    ) {
+        (Ok(repetitions), Some(expression)) => (repetitions, format!("{}{}", marker, expression)),
+        _ => (1, request.to_owned()),
     }
 }
 
-fn roll(a: u32, b: u32, should_explode: bool) -> i32 {
-    (1..=a).map(|_| roll_single(b, should_explode)).sum()
+/// Returns the action dice for a step. Steps below 1 are treated as step 1.
+pub fn step_action_dice(step: i32) -> Option<&'static str> {
+    STEP_ACTION_DICE.get((step.max(1) - 1) as usize).copied()
 }
 
-fn roll_single(x: u32, should_explode: bool) -> i32 {
-    if x < 1 {
-        return 0;
-    }
+pub fn has_step(input: &str) -> bool {
+    roll_part(input).contains('[')
+}
+
+/// Replaces steps like `[8]` or `[6+2]` in the roll part of the input by
+/// their action dice, after adding the step modifier. Unknown steps are kept.
+pub fn expand_steps(input: &str, step_modifier: i32) -> String {
+    let roll = roll_part(input);
+    let text = &input[roll.len()..];
 
-    let mut rng = thread_rng();
-    let mut result: i32 = 0;
-    loop {
-        let i: u32 = rng.gen_range(1, x + 1);
-        result += i as i32;
-        if !should_explode || (i < x && x > 1) || x == 1 {
-            break;
+    let mut expanded = String::new();
+    let mut rest = roll;
+    while let Some(start) = rest.find('[') {
+        let end = match rest[start..].find(']') {
+            Some(end) => start + end,
+            None => break,
+        };
+        expanded.push_str(&rest[..start]);
+        match parse_step(&rest[start + 1..end]).and_then(|s| step_action_dice(s + step_modifier)) {
+            Some(dice) => expanded.push_str(dice),
+            None => expanded.push_str(&rest[start..=end]),
         }
+        rest = &rest[end + 1..];
     }
-    result
+    expanded.push_str(rest);
+    expanded.push_str(text);
+
+    expanded
+}
+
+/// Inserts a note right after the roll part of the input, i.e. the applied
+/// effects, so that the description of the roll stays at the end
+pub fn annotate_roll(input: &str, note: &str) -> String {
+    let roll = roll_part(input);
+    format!("{} ({}){}", roll, note, &input[roll.len()..])
+}
+
+fn parse_step(input: &str) -> Option<i32> {
+    let plus_minus: &[char] = &['+', '-'][..];
+
+    let mut separators: VecDeque<&str> = input.matches(plus_minus).collect();
+    if !input.starts_with(plus_minus) {
+        separators.push_front("+");
+    }
+
+    input
+        .split(plus_minus)
+        .zip(separators)
+        .map(|(part, sign)| format!("{}{}", sign, part.trim()).parse::<i32>().ok())
+        .sum()
+}
+
+fn roll_part(input: &str) -> &str {
+    input.split(' ').next().unwrap()
 }
 
 fn strip_text(input: &str) -> &str {
@@ -127,7 +511,18 @@ pub fn hide_roll_part(input: &str) -> String {
 
 #[cfg(test)]
 mod test {
-    use super::get_results;
+    use super::{
+        annotate_roll, expand_steps, split_repetitions, totals, DiceExpression, DiceLimits, Keep,
+        TermKind,
+    };
+
+    fn parse(request: &str) -> DiceExpression {
+        super::parse(request, &DiceLimits::default()).unwrap()
+    }
+
+    fn get_results(request: &str) -> Vec<i32> {
+        totals(&parse(request).roll())
+    }
 
     #[test]
     fn test_number() {
@@ -153,4 +548,114 @@ mod test {
     fn test_dice_without_prefix() {
         assert_eq!(vec![1], get_results(&"d1"));
     }
+
+    #[test]
+    fn test_expand_steps() {
+        assert_eq!("!!2d6 Spear", expand_steps("!![8] Spear", 0));
+        assert_eq!("!!1d8* [3]", expand_steps("!![6+2]* [3]", -3));
+        assert_eq!("!!1d4-2", expand_steps("!![2]", -5));
+        assert_eq!("!![31]+1d6", expand_steps("!![31]+[4]", 0));
+    }
+
+    #[test]
+    fn test_annotate_roll() {
+        assert_eq!(
+            "!!2d6 (Wounded -2) (ini) attack",
+            annotate_roll("!!2d6 (ini) attack", "Wounded -2")
+        );
+        assert_eq!("!!1d8 (Aid +1)", annotate_roll("!!1d8", "Aid +1"));
+    }
+
+    #[test]
+    fn test_roll_parsed_expression_repeatedly() {
+        let expression = parse("2d1+3");
+        assert_eq!(vec![2, 3], totals(&expression.roll()));
+        assert_eq!(vec![2, 3], totals(&expression.roll()));
+    }
+
+    #[test]
+    fn test_split_repetitions() {
+        assert_eq!(
+            (5, "![8] Spear".to_owned()),
+            split_repetitions("!5x[8] Spear")
+        );
+        assert_eq!((3, "!!2d6".to_owned()), split_repetitions("!!3x2d6"));
+        assert_eq!((1, "!!2d6".to_owned()), split_repetitions("!!2d6"));
+        assert_eq!((1, "!4+2".to_owned()), split_repetitions("!4+2"));
+    }
+
+    #[test]
+    fn test_keep_and_drop() {
+        assert_eq!(vec![3], get_results("4d1kh3"));
+        assert_eq!(vec![1], get_results("2d1kl1"));
+        assert_eq!(vec![3, -1], get_results("4d1dl1-2d1dh1"));
+
+        let result = parse("4d1kh3").roll();
+        assert_eq!(1, result[0].dice.iter().filter(|d| d.dropped).count());
+    }
+
+    #[test]
+    fn test_reroll() {
+        let result = parse("d1r1").roll();
+        assert_eq!(1, result[0].total);
+        assert_eq!(Some(1), result[0].dice[0].rerolled);
+    }
+
+    #[test]
+    fn test_explode_modifiers() {
+        let explode_at = |request: &str| match &parse(request).terms[0].kind {
+            TermKind::Dice(dice) => dice.explode_at,
+            TermKind::Number(_) => None,
+        };
+        assert_eq!(Some(6), explode_at("2d6!"));
+        assert_eq!(Some(9), explode_at("d10!>9"));
+        assert_eq!(Some(8), explode_at("!d8"));
+        assert_eq!(None, explode_at("d6"));
+        assert_eq!(None, explode_at("d6!>1"));
+        assert_eq!(vec![1], get_results("!d1"));
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = DiceLimits::default();
+        assert!(super::parse("100d6", &limits).is_ok());
+        assert!(super::parse("4000000000d6", &limits).is_err());
+        assert!(super::parse("40000000000d6", &limits).is_err());
+        assert!(super::parse("60d6+41d6", &limits).is_err());
+        assert!(super::parse("d1001", &limits).is_err());
+        assert!(super::parse("1+1+1+1+1+1+1+1+1+1+1+1+1+1+1+1+1+1+1+1+1", &limits).is_err());
+        assert!(super::parse("2147483647+1", &limits).is_err());
+        assert!(super::parse("99999999999", &limits).is_err());
+
+        // explosions are capped and count towards the largest result
+        let limits = DiceLimits {
+            max_explosions: 3,
+            ..limits
+        };
+        let expression = super::parse("d2!", &limits).unwrap();
+        for _ in 0..100 {
+            assert!(expression.roll()[0].dice[0].rolls.len() <= 4);
+        }
+        let limits = DiceLimits {
+            max_explosions: u32::MAX,
+            ..limits
+        };
+        assert!(super::parse("!100d1000", &limits).is_err());
+
+        // low explosion thresholds
+        let limits = DiceLimits::default();
+        assert!(super::parse("d10!>6", &limits).is_ok());
+        assert!(super::parse("d6!>3", &limits).is_err());
+        assert!(super::parse("5d1000!>2", &limits).is_err());
+    }
+
+    #[test]
+    fn test_invalid_modifiers() {
+        assert_eq!(vec![-666], get_results("4d6kx"));
+        assert_eq!(vec![-666], get_results("4d6q1"));
+        assert!(matches!(
+            &parse("4d6dl1").terms[0].kind,
+            TermKind::Dice(d) if matches!(d.keep, Some(Keep::Highest(3)))
+        ));
+    }
 }
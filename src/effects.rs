@@ -0,0 +1,160 @@
+//! Timed status effects on characters, i.e. "Knocked Down" with -3 for one
+//! round. The modifiers of all effects on a character are added to the step
+//! of its step rolls (i.e. `!![8]`). Effects with a duration expire when the
+//! combat round advances.
+
+use crate::arguments::split_arguments;
+use crate::messages::EffectDTO;
+
+pub enum EffectCommand {
+    Add(EffectDTO),
+    Remove { target: String, name: String },
+    Clear { target: String },
+}
+
+impl EffectCommand {
+    pub fn target(&self) -> &str {
+        match self {
+            EffectCommand::Add(effect) => &effect.target,
+            EffectCommand::Remove { target, .. } | EffectCommand::Clear { target } => target,
+        }
+    }
+}
+
+pub struct Effects {
+    effects: Vec<EffectDTO>,
+}
+
+impl Effects {
+    pub fn new() -> Self {
+        Effects { effects: vec![] }
+    }
+
+    /// Adds an effect, replacing an effect with the same name on the same target
+    pub fn add(&mut self, effect: EffectDTO) {
+        self.remove(&effect.target, &effect.name);
+        self.effects.push(effect);
+    }
+
+    pub fn remove(&mut self, target: &str, name: &str) -> bool {
+        let count = self.effects.len();
+        self.effects.retain(|e| {
+            !(e.target.eq_ignore_ascii_case(target) && e.name.eq_ignore_ascii_case(name))
+        });
+        count != self.effects.len()
+    }
+
+    pub fn clear(&mut self, target: &str) -> bool {
+        let count = self.effects.len();
+        self.effects
+            .retain(|e| !e.target.eq_ignore_ascii_case(target));
+        count != self.effects.len()
+    }
+
+    /// All effects on the given target
+    pub fn on(&self, target: &str) -> Vec<&EffectDTO> {
+        self.effects
+            .iter()
+            .filter(|e| e.target.eq_ignore_ascii_case(target))
+            .collect()
+    }
+
+    /// Counts down the remaining rounds of all effects and returns the
+    /// expired ones
+    pub fn advance_round(&mut self) -> Vec<EffectDTO> {
+        let mut expired = vec![];
+        let mut active = vec![];
+        for mut effect in self.effects.drain(..) {
+            match effect.rounds.as_mut() {
+                Some(rounds) if *rounds <= 1 => expired.push(effect),
+                Some(rounds) => {
+                    *rounds -= 1;
+                    active.push(effect);
+                }
+                None => active.push(effect),
+            }
+        }
+        self.effects = active;
+
+        expired
+    }
+
+    pub fn to_dto(&self) -> Vec<EffectDTO> {
+        self.effects.clone()
+    }
+}
+
+/// Parses the arguments of `/effect`, i.e.
+/// `add Thorin "Knocked Down" -3 rounds:1`, `remove Thorin "Knocked Down"` or
+/// `clear Thorin`
+pub fn parse_effect_command(input: &str) -> Result<EffectCommand, String> {
+    let arguments = split_arguments(input);
+    let arguments: Vec<&str> = arguments.iter().map(|a| a.as_str()).collect();
+
+    match arguments.as_slice() {
+        ["add", target, name, modifier] | ["add", target, name, modifier, _] => {
+            let modifier = modifier
+                .parse::<i32>()
+                .map_err(|_| format!("invalid modifier '{}'", modifier))?;
+            let rounds = match arguments.get(4) {
+                Some(rounds) => Some(
+                    rounds
+                        .strip_prefix("rounds:")
+                        .and_then(|r| r.parse::<u32>().ok())
+                        .filter(|r| *r > 0)
+                        .ok_or_else(|| format!("invalid duration '{}'", rounds))?,
+                ),
+                None => None,
+            };
+            Ok(EffectCommand::Add(EffectDTO {
+                target: target.to_string(),
+                name: name.to_string(),
+                modifier,
+                rounds,
+            }))
+        }
+        ["remove", target, name] => Ok(EffectCommand::Remove {
+            target: target.to_string(),
+            name: name.to_string(),
+        }),
+        ["clear", target] => Ok(EffectCommand::Clear {
+            target: target.to_string(),
+        }),
+        _ => Err(concat!(
+            "usage: /effect add <name> \"<effect>\" <modifier> [rounds:<n>]",
+            " | /effect remove <name> \"<effect>\" | /effect clear <name>"
+        )
+        .to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_effects_expire() {
+        let mut effects = Effects::new();
+        match parse_effect_command(r#"add Thorin "Knocked Down" -3 rounds:1"#) {
+            Ok(EffectCommand::Add(effect)) => effects.add(effect),
+            _ => panic!("effect must be parsed"),
+        }
+        match parse_effect_command(r#"add thorin "Harden Armor" 2"#) {
+            Ok(EffectCommand::Add(effect)) => effects.add(effect),
+            _ => panic!("effect must be parsed"),
+        }
+        assert_eq!(2, effects.on("Thorin").len());
+
+        let expired = effects.advance_round();
+        assert_eq!(1, expired.len());
+        assert_eq!("Knocked Down", expired[0].name);
+        assert_eq!(1, effects.on("Thorin").len());
+    }
+
+    #[test]
+    fn test_invalid_effect_command() {
+        assert!(parse_effect_command("add Thorin Dazed x").is_err());
+        assert!(parse_effect_command("add Thorin Dazed -1 rounds:0").is_err());
+        assert!(parse_effect_command("drop Thorin").is_err());
+    }
+}
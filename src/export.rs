@@ -0,0 +1,274 @@
+//! Renders the chat log of a room as Markdown, HTML or JSON, i.e. to paste a
+//! session into a campaign wiki.
+
+use crate::messages::{BookmarkKind, DieResultDTO, MessageKind, TermResultDTO, TextMessageDTO};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Markdown,
+    Html,
+    Json,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "md" | "markdown" => Some(Format::Markdown),
+            "html" => Some(Format::Html),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Markdown => "text/markdown; charset=utf-8",
+            Format::Html => "text/html; charset=utf-8",
+            Format::Json => "application/json",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Markdown => "md",
+            Format::Html => "html",
+            Format::Json => "json",
+        }
+    }
+}
+
+pub fn render(room_name: &str, messages: &[TextMessageDTO], format: Format) -> String {
+    match format {
+        Format::Markdown => {
+            let mut log = format!("# {}\n\n", room_name);
+            for message in messages {
+                match message.bookmark() {
+                    Some(bookmark) if bookmark.kind == BookmarkKind::Session => {
+                        log.push_str(&format!("\n## {}\n\n", bookmark.title))
+                    }
+                    Some(bookmark) => log.push_str(&format!("\n### {}\n\n", bookmark.title)),
+                    None => log.push_str(&format!("* {}\n", markdown_entry(message))),
+                }
+            }
+            log
+        }
+        Format::Html => {
+            let mut log = format!(
+                concat!(
+                    "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\" />\n",
+                    "<title>{0}</title>\n</head>\n<body>\n<h1>{0}</h1>\n<ul>\n"
+                ),
+                escape_html(room_name)
+            );
+            for message in messages {
+                match message.bookmark() {
+                    Some(bookmark) => log.push_str(&format!(
+                        "</ul>\n<{0}>{1}</{0}>\n<ul>\n",
+                        if bookmark.kind == BookmarkKind::Session {
+                            "h2"
+                        } else {
+                            "h3"
+                        },
+                        escape_html(&bookmark.title)
+                    )),
+                    None => log.push_str(&format!("<li>{}</li>\n", html_entry(message))),
+                }
+            }
+            log.push_str("</ul>\n</body>\n</html>\n");
+            log
+        }
+        Format::Json => serde_json::to_string_pretty(messages).unwrap(),
+    }
+}
+
+fn markdown_entry(message: &TextMessageDTO) -> String {
+    // system messages use <br> for line breaks
+    let text = message.message().replace("<br>", "  \n  ");
+    let mut entry = match (message.display_name(), message.kind()) {
+        (Some(name), MessageKind::Emote) => {
+            format!("`{}` _**{}** {}_", timestamp(message), name, text)
+        }
+        (Some(name), MessageKind::Ooc) => {
+            format!("`{}` **{}:** (OOC) {}", timestamp(message), name, text)
+        }
+        (Some(name), _) => format!("`{}` **{}:** {}", timestamp(message), name, text),
+        (None, _) => format!("`{}` _{}_", timestamp(message), text),
+    };
+    for result in results(message) {
+        entry.push_str(&format!("  \n  **{}**", result));
+    }
+    for line in breakdown(message, |text| format!("~~{}~~", text)) {
+        entry.push_str(&format!("  \n  {}", line));
+    }
+    entry
+}
+
+fn html_entry(message: &TextMessageDTO) -> String {
+    let text = escape_html(message.message()).replace("&lt;br&gt;", "<br>");
+    let mut entry = match (message.display_name(), message.kind()) {
+        (Some(name), MessageKind::Emote) => format!(
+            "<time>{}</time> <i><b>{}</b> {}</i>",
+            timestamp(message),
+            escape_html(name),
+            text
+        ),
+        (Some(name), MessageKind::Ooc) => format!(
+            "<time>{}</time> <b>{}:</b> <small>(OOC) {}</small>",
+            timestamp(message),
+            escape_html(name),
+            text
+        ),
+        (Some(name), _) => format!(
+            "<time>{}</time> <b>{}:</b> {}",
+            timestamp(message),
+            escape_html(name),
+            text
+        ),
+        (None, _) => format!("<time>{}</time> <i>{}</i>", timestamp(message), text),
+    };
+    for result in results(message) {
+        entry.push_str(&format!("<br><b>{}</b>", result));
+    }
+    for line in breakdown(message, |text| format!("<s>{}</s>", text)) {
+        entry.push_str(&format!("<br><small>{}</small>", line));
+    }
+    entry
+}
+
+fn timestamp(message: &TextMessageDTO) -> String {
+    message.time().format("%Y-%m-%d %H:%M:%S UTC").to_string()
+}
+
+/// The results of a roll like `4 + 3 = 7`, one per repetition
+fn results(message: &TextMessageDTO) -> Vec<String> {
+    let describe = |results: &[i32]| {
+        let terms: Vec<String> = results.iter().map(|r| r.to_string()).collect();
+        format!("{} = {}", terms.join(" + "), results.iter().sum::<i32>())
+    };
+
+    match (message.dice_results(), message.repeated_dice_results()) {
+        (Some(results), _) => vec![describe(results)],
+        (None, Some(repeated)) => repeated.iter().map(|r| describe(r)).collect(),
+        (None, None) => vec![],
+    }
+}
+
+/// The single dice of a roll like `2d6: 6→2, 3`, strikethrough faces are
+/// rerolled or dropped
+fn breakdown(message: &TextMessageDTO, strike: fn(String) -> String) -> Vec<String> {
+    let describe_term = |term: &TermResultDTO| {
+        let dice: Vec<String> = term.dice.iter().map(|d| describe_die(d, strike)).collect();
+        format!("{}: {}", term.expression, dice.join(", "))
+    };
+
+    message
+        .breakdown()
+        .unwrap_or(&[])
+        .iter()
+        .map(|terms| {
+            let terms: Vec<String> = terms
+                .iter()
+                .filter(|t| !t.dice.is_empty())
+                .map(describe_term)
+                .collect();
+            terms.join(" | ")
+        })
+        .collect()
+}
+
+fn describe_die(die: &DieResultDTO, strike: fn(String) -> String) -> String {
+    let rolls: Vec<String> = die.rolls.iter().map(|r| r.to_string()).collect();
+    let mut text = rolls.join("→");
+    if let Some(rerolled) = die.rerolled {
+        text = format!("{}↻{}", strike(rerolled.to_string()), text);
+    }
+    if die.dropped {
+        text = strike(text);
+    }
+    text
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dice::{parse, DiceLimits};
+
+    #[test]
+    fn test_markdown() {
+        let roll = parse("2d6kh1", &DiceLimits::default()).unwrap().roll();
+        let message = TextMessageDTO::dice_result("!2d6kh1 Spear", &vec![roll[0].total], "Thorin")
+            .with_breakdown(vec![roll]);
+        let log = render(
+            "Main",
+            &[TextMessageDTO::system("'Thorin' joined the room"), message],
+            Format::Markdown,
+        );
+
+        assert!(log.starts_with("# Main\n\n"));
+        assert!(log.contains("_'Thorin' joined the room_"));
+        assert!(log.contains("**Thorin:** !2d6kh1 Spear"));
+        assert!(log.contains("2d6kh1: "));
+        assert!(log.contains("~~"));
+    }
+
+    #[test]
+    fn test_message_kinds() {
+        let messages = [
+            TextMessageDTO::chat("draws his sword", "Thorin").with_kind(MessageKind::Emote),
+            TextMessageDTO::chat("brb pizza", "Thorin").with_kind(MessageKind::Ooc),
+        ];
+
+        let log = render("Main", &messages, Format::Markdown);
+        assert!(log.contains("_**Thorin** draws his sword_"));
+        assert!(log.contains("**Thorin:** (OOC) brb pizza"));
+
+        let npc = TextMessageDTO::chat("The gates are closed.", "GM")
+            .with_alias(Some("Vizier Kratt".to_string()));
+        let log = render("Main", &[npc], Format::Markdown);
+        assert!(log.contains("**Vizier Kratt:** The gates are closed."));
+    }
+
+    #[test]
+    fn test_bookmarks() {
+        let messages = [
+            TextMessageDTO::bookmark_message(BookmarkKind::Session, "Chapter 3: Parlainth"),
+            TextMessageDTO::bookmark_message(BookmarkKind::Scene, "The Kaer gate"),
+        ];
+
+        let log = render("Main", &messages, Format::Markdown);
+        assert!(log.contains("\n## Chapter 3: Parlainth\n"));
+        assert!(log.contains("\n### The Kaer gate\n"));
+
+        let log = render("Main", &messages, Format::Html);
+        assert!(log.contains("<h2>Chapter 3: Parlainth</h2>"));
+    }
+
+    #[test]
+    fn test_html_is_escaped() {
+        let log = render(
+            "Main",
+            &[
+                TextMessageDTO::chat("<script>", "Thorin"),
+                TextMessageDTO::system("Round 2<br>'Dazed' on Thorin expired"),
+            ],
+            Format::Html,
+        );
+
+        assert!(log.contains("&lt;script&gt;"));
+        assert!(log.contains("Round 2<br>"));
+    }
+
+    #[test]
+    fn test_format_from_name() {
+        assert_eq!(Some(Format::Markdown), Format::from_name("md"));
+        assert_eq!(Some(Format::Json), Format::from_name("JSON"));
+        assert_eq!(None, Format::from_name("pdf"));
+    }
+}
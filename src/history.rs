@@ -0,0 +1,277 @@
+//! The messages sent to a room, kept for exporting and replaying the chat log.
+//! The details of hidden rolls are only visible to the session which rolled
+//! them, session ids are not kept across restarts.
+//! Session bookmarks split the history into sessions which can be fetched
+//! separately.
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::messages::{BookmarkKind, TextMessageDTO};
+
+/// How many messages a room keeps unless configured otherwise
+pub const DEFAULT_HISTORY_SIZE: usize = 1000;
+
+/// Which part of the history to fetch
+pub enum SessionFilter {
+    All,
+    /// The latest session, or everything if no session was started
+    Current,
+    /// The session(s) with this title
+    Titled(String),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct History {
+    entries: VecDeque<HistoryEntry>,
+    #[serde(skip, default = "default_limit")]
+    limit: usize,
+    /// Id of the last message
+    #[serde(default)]
+    last_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct HistoryEntry {
+    /// The message as it was sent to the room
+    message: TextMessageDTO,
+    /// The complete message of a hidden roll
+    details: Option<TextMessageDTO>,
+    /// Id of the session which sent the message, unknown after a restart
+    #[serde(skip)]
+    author: Option<usize>,
+}
+
+fn default_limit() -> usize {
+    DEFAULT_HISTORY_SIZE
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(DEFAULT_HISTORY_SIZE)
+    }
+}
+
+impl History {
+    pub fn new(limit: usize) -> Self {
+        History {
+            entries: VecDeque::new(),
+            limit,
+            last_id: 0,
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Changes how many messages are kept, dropping the oldest ones if needed
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        while self.entries.len() > limit {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Adds a message of the session `author`, dropping the oldest one if the
+    /// history is full. Returns the message with its new id.
+    pub fn push(
+        &mut self,
+        message: TextMessageDTO,
+        details: Option<TextMessageDTO>,
+        author: Option<usize>,
+    ) -> TextMessageDTO {
+        if self.entries.len() >= self.limit {
+            self.entries.pop_front();
+        }
+        self.last_id += 1;
+        let message = message.with_id(self.last_id);
+        let details = details.map(|details| details.with_id(self.last_id));
+        self.entries.push_back(HistoryEntry {
+            message: message.clone(),
+            details,
+            author,
+        });
+        message
+    }
+
+    pub fn get(&self, id: u64) -> Option<&TextMessageDTO> {
+        self.entries
+            .iter()
+            .find(|e| e.message.id() == Some(id))
+            .map(|e| &e.message)
+    }
+
+    /// Whether the session sent the message, names can be taken by anyone
+    pub fn is_author(&self, id: u64, session: usize) -> bool {
+        self.entries
+            .iter()
+            .any(|e| e.message.id() == Some(id) && e.author == Some(session))
+    }
+
+    /// Replaces the text of a message, returns the edited message
+    pub fn edit(&mut self, id: u64, text: &str) -> Option<TextMessageDTO> {
+        let entry = self
+            .entries
+            .iter_mut()
+            .find(|e| e.message.id() == Some(id))?;
+        entry.message.edit(text);
+        Some(entry.message.clone())
+    }
+
+    pub fn delete(&mut self, id: u64) -> bool {
+        let count = self.entries.len();
+        self.entries.retain(|e| e.message.id() != Some(id));
+        self.entries.len() != count
+    }
+
+    /// The messages of a session as the session `viewer` may see them,
+    /// starting at `since` (in milliseconds since the epoch)
+    pub fn visible_to(
+        &self,
+        viewer: Option<usize>,
+        since: Option<i64>,
+        session: &SessionFilter,
+    ) -> Vec<TextMessageDTO> {
+        let session = match session {
+            SessionFilter::All => None,
+            SessionFilter::Current => self.current_session(),
+            SessionFilter::Titled(title) => Some(title.clone()),
+        };
+        let mut in_session = session.is_none();
+
+        self.entries
+            .iter()
+            .filter(|e| {
+                if let (Some(session), Some(bookmark)) = (session.as_ref(), e.message.bookmark()) {
+                    if bookmark.kind == BookmarkKind::Session {
+                        in_session = bookmark.title.eq_ignore_ascii_case(session);
+                    }
+                }
+                in_session
+            })
+            .filter(|e| e.message.time().timestamp_millis() >= since.unwrap_or(i64::MIN))
+            .map(|e| match &e.details {
+                Some(details) if viewer.is_some() && e.author == viewer => details.clone(),
+                _ => e.message.clone(),
+            })
+            .collect()
+    }
+
+    /// Title of the latest session
+    pub fn current_session(&self) -> Option<String> {
+        self.entries
+            .iter()
+            .rev()
+            .filter_map(|e| e.message.bookmark())
+            .find(|b| b.kind == BookmarkKind::Session)
+            .map(|b| b.title.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hidden_details() {
+        let mut history = History::default();
+        history.push(TextMessageDTO::chat("Hello", "Thorin"), None, None);
+        history.push(
+            TextMessageDTO::dice_result("!*hidden* Spear", &vec![12], "Thorin"),
+            Some(TextMessageDTO::dice_result(
+                "!!2d6* Spear",
+                &vec![8, 4],
+                "Thorin",
+            )),
+            Some(1),
+        );
+
+        let own = history.visible_to(Some(1), None, &SessionFilter::All);
+        assert_eq!(2, own.len());
+        assert_eq!("!!2d6* Spear", own[1].message());
+
+        // another session named like the roller doesn't get the details
+        let other = history.visible_to(Some(2), None, &SessionFilter::All);
+        assert_eq!("!*hidden* Spear", other[1].message());
+        assert_eq!(
+            "!*hidden* Spear",
+            history.visible_to(None, None, &SessionFilter::All)[1].message()
+        );
+    }
+
+    #[test]
+    fn test_bounded() {
+        let mut history = History::new(10);
+        for i in 0..15 {
+            history.push(TextMessageDTO::chat(&i.to_string(), "Thorin"), None, None);
+        }
+
+        let messages = history.visible_to(None, None, &SessionFilter::All);
+        assert_eq!(10, messages.len());
+        assert_eq!("5", messages[0].message());
+
+        history.set_limit(3);
+        assert_eq!(
+            "12",
+            history.visible_to(None, None, &SessionFilter::All)[0].message()
+        );
+        assert!(history
+            .visible_to(None, Some(i64::MAX), &SessionFilter::All)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_edit_and_delete() {
+        let mut history = History::default();
+        let first = history.push(TextMessageDTO::chat("Helo", "Thorin"), None, Some(1));
+        let second = history.push(TextMessageDTO::chat("Hi", "Gimli"), None, Some(2));
+        assert_eq!(Some(1), first.id());
+        assert_eq!(Some(2), second.id());
+        assert!(history.is_author(1, 1));
+        assert!(!history.is_author(1, 2));
+        assert!(!history.is_author(3, 1));
+
+        let edited = history.edit(1, "Hello").unwrap();
+        assert_eq!("Hello", edited.message());
+        assert_eq!("Hello", history.get(1).unwrap().message());
+        assert!(history.edit(3, "Hello").is_none());
+
+        assert!(history.delete(2));
+        assert!(!history.delete(2));
+        assert!(history.get(2).is_none());
+        assert_eq!(1, history.visible_to(None, None, &SessionFilter::All).len());
+    }
+
+    #[test]
+    fn test_sessions() {
+        let mut history = History::default();
+        history.push(TextMessageDTO::chat("before", "Thorin"), None, None);
+        history.push(
+            TextMessageDTO::bookmark_message(BookmarkKind::Session, "Chapter 3"),
+            None,
+            None,
+        );
+        history.push(
+            TextMessageDTO::bookmark_message(BookmarkKind::Scene, "The Kaer gate"),
+            None,
+            None,
+        );
+        history.push(TextMessageDTO::chat("in chapter 3", "Thorin"), None, None);
+        history.push(
+            TextMessageDTO::bookmark_message(BookmarkKind::Session, "Chapter 4"),
+            None,
+            None,
+        );
+        history.push(TextMessageDTO::chat("in chapter 4", "Thorin"), None, None);
+
+        let chapter = history.visible_to(None, None, &SessionFilter::Titled("chapter 3".into()));
+        assert_eq!(3, chapter.len());
+        assert_eq!("in chapter 3", chapter[2].message());
+
+        let current = history.visible_to(None, None, &SessionFilter::Current);
+        assert_eq!(2, current.len());
+        assert_eq!(6, history.visible_to(None, None, &SessionFilter::All).len());
+    }
+}
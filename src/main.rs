@@ -1,66 +1,336 @@
 #[macro_use]
 extern crate log;
 
-use crate::dice::{get_results, hide_roll_part, is_hidden_roll};
 use crate::messages::TextMessageDTO;
-use std::env;
+use std::collections::BTreeMap;
+use std::sync::Arc;
 
 use std::time::{Duration, Instant};
 
 use actix::*;
 use actix_files as fs;
-use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{http::header, web, App, Error, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws;
 
-use messages::OutgoingMessageDTO;
-use room::LeaveRoomMessage;
+use access::{is_valid_password, split_credential, Credential};
+use arguments::{split_arguments, split_first_argument};
+use clap::Parser;
+use config::{Cli, Config};
+use dice::DiceLimits;
+use futures::future;
+use history::SessionFilter;
+use messages::{
+    BookmarkKind, ErrorCode, ErrorDTO, ExportDTO, HistoryDTO, KickDTO, MessageKind,
+    OutgoingMessageDTO, Presence, RoomEnvelopeDTO,
+};
+use ratelimit::{Action, ActionBuckets, AddressLimiter, TokenBucket};
+use room::{LeaveRoomMessage, RoomSettings};
+use serde::Deserialize;
 
+mod access;
+mod admin;
+mod arguments;
+mod board;
+mod combat;
+mod config;
 mod dice;
+mod effects;
+mod export;
 mod greet;
+mod history;
 mod messages;
+mod metrics;
+mod moderation;
+mod odds;
+mod ratelimit;
 mod room;
 mod server;
+mod shutdown;
+mod stats;
+mod storage;
+mod tls;
 
-/// How often heartbeat pings are sent
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
-/// How long before lack of client response causes a timeout
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the health and metrics endpoints wait for the actors
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
 
-#[derive(Message)]
-#[rtype(result = "String")]
-struct GetNameMsg;
+#[derive(Deserialize)]
+struct OddsQuery {
+    roll: String,
+    target: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct LogQuery {
+    format: Option<String>,
+    /// Milliseconds since the epoch
+    since: Option<i64>,
+    /// Title of a session
+    session: Option<String>,
+    /// Needed for locked rooms, like `/join Room password:secret`
+    password: Option<String>,
+    /// Token of an invite link of the room
+    invite: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ChatQuery {
+    /// Resume token of an earlier session
+    resume: Option<String>,
+    /// Version of the client, the user agent is used if not given
+    version: Option<String>,
+    /// Room of an invite link
+    room: Option<String>,
+    /// Token of an invite link
+    invite: Option<String>,
+}
 
 /// Entry point for our websocket route
 async fn chat_route(
     req: HttpRequest,
     stream: web::Payload,
+    query: web::Query<ChatQuery>,
     srv: web::Data<Addr<server::ChatServer>>,
+    config: web::Data<Config>,
+    address_limiter: web::Data<AddressLimiter>,
 ) -> Result<HttpResponse, Error> {
+    let now = Instant::now();
+    let forwarded_for = req
+        .headers()
+        .get("X-Forwarded-For")
+        .and_then(|value| value.to_str().ok());
+    let remote_addr = config
+        .client_addr(req.peer_addr().map(|addr| addr.ip()), forwarded_for)
+        .map(|addr| addr.to_string());
+    if let Some(remote_addr) = remote_addr.as_ref() {
+        if !address_limiter.try_connect(remote_addr, now) {
+            warn!("Too many connections from {}", remote_addr);
+            return Ok(HttpResponse::TooManyRequests().body("too many connections"));
+        }
+    }
+
+    let query = query.into_inner();
+    let invite = match (query.room, query.invite) {
+        (Some(room), Some(token)) => {
+            if !is_valid_invite(&srv, &room, &token).await? {
+                return Ok(HttpResponse::Forbidden().body("invalid invite"));
+            }
+            Some((room, token))
+        }
+        _ => None,
+    };
+    let client_version = query.version.or_else(|| {
+        req.headers()
+            .get(header::USER_AGENT)
+            .and_then(|agent| agent.to_str().ok())
+            .map(str::to_string)
+    });
+
     ws::start(
         WsChatSession {
             id: 0,
+            resume_token: query.resume,
+            remote_addr,
+            client_version,
+            admin: false,
+            admin_token: config.admin_token.clone(),
+            invite,
+            buckets: ActionBuckets::new(&address_limiter.limits().session, now),
+            strikes: TokenBucket::new(address_limiter.limits().strikes, now),
+            address_limiter: address_limiter.into_inner(),
+            max_message_length: config.max_message_length,
+            dice_limits: config.dice_limits,
             hb: Instant::now(),
+            heartbeat_interval: config.heartbeat_interval(),
+            client_timeout: config.client_timeout(),
+            last_activity: Instant::now(),
+            idle_timeout: config.idle_timeout(),
+            idle: false,
+            away: false,
             name: None,
             server_addr: srv.get_ref().clone(),
-            room_addr: None,
+            rooms: BTreeMap::new(),
+            active_room: None,
         },
         &req,
         stream,
     )
 }
 
+async fn is_valid_invite(
+    srv: &Addr<server::ChatServer>,
+    room: &str,
+    token: &str,
+) -> Result<bool, Error> {
+    let room_addr = srv
+        .send(server::FindRoom {
+            name: room.to_owned(),
+        })
+        .await
+        .map_err(ErrorInternalServerError)?;
+    match room_addr {
+        Some(room_addr) => room_addr
+            .send(room::CheckInvite(token.to_owned()))
+            .await
+            .map_err(ErrorInternalServerError),
+        None => Ok(false),
+    }
+}
+
+/// Calculates the odds of a roll, i.e. `/odds?roll=[10]&target=12`
+async fn odds_route(query: web::Query<OddsQuery>, config: web::Data<Config>) -> HttpResponse {
+    match odds::calculate(&query.roll, query.target, &config.dice_limits) {
+        Ok(odds) => HttpResponse::Ok().json(odds),
+        Err(error) => HttpResponse::BadRequest().body(error),
+    }
+}
+
+/// Exports the chat log of a room, i.e. `/rooms/Main/log?format=html`. The
+/// details of hidden rolls are left out, locked rooms need a `password` or
+/// an `invite` token.
+async fn log_route(
+    name: web::Path<String>,
+    query: web::Query<LogQuery>,
+    srv: web::Data<Addr<server::ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    let format = match query.format.as_deref().map(export::Format::from_name) {
+        None => export::Format::Markdown,
+        Some(Some(format)) => format,
+        Some(None) => return Ok(HttpResponse::BadRequest().body("format must be md, html or json")),
+    };
+
+    let room = match srv
+        .send(server::FindRoom {
+            name: name.into_inner(),
+        })
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        Some(room) => room,
+        None => return Ok(HttpResponse::NotFound().body("unknown room")),
+    };
+    let query = query.into_inner();
+    let credential = match (query.password, query.invite) {
+        (_, Some(token)) => Some(Credential::Invite(token)),
+        (Some(password), None) => Some(Credential::Password(password)),
+        (None, None) => None,
+    };
+    let log = match room
+        .send(room::LogMessage {
+            viewer: None,
+            credential,
+            since: query.since,
+            session: match query.session {
+                Some(title) => SessionFilter::Titled(title),
+                None => SessionFilter::All,
+            },
+        })
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        Ok(log) => log,
+        Err(error) => return Ok(HttpResponse::Forbidden().body(error)),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .body(export::render(&log.room_name, &log.messages, format)))
+}
+
+/// Liveness probe
+async fn health_route() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+/// Readiness probe, checks that the chat server answers
+async fn ready_route(srv: web::Data<Addr<server::ChatServer>>) -> HttpResponse {
+    if shutdown::is_shutting_down() {
+        return HttpResponse::ServiceUnavailable().body("shutting down");
+    }
+    match srv
+        .send(server::GetServerState)
+        .timeout(PROBE_TIMEOUT)
+        .await
+    {
+        Ok(_) => HttpResponse::Ok().body("ok"),
+        Err(_) => HttpResponse::ServiceUnavailable().body("chat server is not responding"),
+    }
+}
+
+/// Prometheus metrics of the chat server and all rooms
+async fn metrics_route(srv: web::Data<Addr<server::ChatServer>>) -> Result<HttpResponse, Error> {
+    let start = Instant::now();
+    let state = srv
+        .send(server::GetServerState)
+        .timeout(PROBE_TIMEOUT)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let lag = start.elapsed();
+
+    let mut rooms = vec![];
+    for room in state.rooms {
+        let start = Instant::now();
+        // rooms that don't answer in time are left out
+        if let Ok(mut room_metrics) = room.send(room::GetRoomMetrics).timeout(PROBE_TIMEOUT).await {
+            room_metrics.lag = start.elapsed();
+            rooms.push(room_metrics);
+        }
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render(&metrics::ServerMetrics {
+            sessions: state.sessions,
+            heartbeat_timeouts: metrics::heartbeat_timeouts(),
+            lag,
+            rooms,
+        })))
+}
+
 pub struct WsChatSession {
-    /// unique session id
+    /// unique session id, 0 until the chat server registered the session
     id: usize,
-    /// Client must send ping at least once per 10 seconds (CLIENT_TIMEOUT),
-    /// otherwise we drop connection.
+    /// Token of the session to resume on connect
+    resume_token: Option<String>,
+    remote_addr: Option<String>,
+    client_version: Option<String>,
+    /// Whether the session authenticated with `/admin`
+    admin: bool,
+    /// Token required by `/admin`, admin commands are disabled if not set
+    admin_token: Option<String>,
+    /// Room and token of the invite link the client was opened with
+    invite: Option<(String, String)>,
+    /// Rate limits of this session
+    buckets: ActionBuckets,
+    /// Rate limit violations, the session is closed when none are left
+    strikes: TokenBucket,
+    /// Rate limits of the remote address
+    address_limiter: Arc<AddressLimiter>,
+    max_message_length: usize,
+    /// Limits of the rolls for `/odds`, the rooms check their own rolls
+    dice_limits: DiceLimits,
+    /// Client must send ping at least once per `client_timeout`, otherwise we
+    /// drop connection.
     hb: Instant,
+    /// How often heartbeat pings are sent
+    heartbeat_interval: Duration,
+    /// How long before lack of client response causes a timeout
+    client_timeout: Duration,
+    /// Time of the last message of the client, pings don't count
+    last_activity: Instant,
+    /// How long without messages until the session is idle
+    idle_timeout: Duration,
+    idle: bool,
+    /// Set with `/away`, until it is used again
+    away: bool,
     /// peer name
     name: Option<String>,
     /// Chat server
     server_addr: Addr<server::ChatServer>,
-    /// Current chat room
-    room_addr: Option<Addr<room::ChatRoom>>,
+    /// Joined chat rooms by name
+    rooms: BTreeMap<String, Addr<room::ChatRoom>>,
+    /// The room messages and commands are sent to
+    active_room: Option<String>,
 }
 
 impl Actor for WsChatSession {
@@ -81,11 +351,19 @@ impl Actor for WsChatSession {
         self.server_addr
             .send(server::Connect {
                 addr: addr.recipient(),
+                resume_token: self.resume_token.take(),
+                remote_addr: self.remote_addr.clone(),
+                client_version: self.client_version.take(),
             })
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
-                    Ok(res) => act.id = res,
+                    Ok(res) => {
+                        act.id = res.id;
+                        if let Some(resumed) = res.resumed {
+                            act.resume(resumed, ctx);
+                        }
+                    }
                     // something is wrong with chat server
                     _ => ctx.stop(),
                 }
@@ -95,10 +373,10 @@ impl Actor for WsChatSession {
     }
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
-        // notify chat server
+        // notify chat server, the session can be resumed for a while
         self.server_addr.do_send(server::Disconnect { id: self.id });
-        if let Some(room) = self.room_addr.as_ref() {
-            room.do_send(LeaveRoomMessage {
+        for room in self.rooms.values() {
+            room.do_send(room::SuspendMember {
                 name: self
                     .name
                     .as_ref()
@@ -116,18 +394,49 @@ impl Handler<room::RoomMessage> for WsChatSession {
     type Result = ();
 
     fn handle(&mut self, msg: room::RoomMessage, ctx: &mut Self::Context) {
-        ctx.text(msg.0.to_json());
-    }
-}
-
-impl Handler<GetNameMsg> for WsChatSession {
-    type Result = String;
+        match msg.room.as_ref() {
+            Some(room) => ctx.text(
+                RoomEnvelopeDTO {
+                    room,
+                    message: &msg.message,
+                }
+                .to_json(),
+            ),
+            None => ctx.text(msg.message.to_json()),
+        }
 
-    fn handle(&mut self, _: GetNameMsg, _: &mut Self::Context) -> Self::Result {
-        self.name
-            .as_ref()
-            .expect("The name must be set if the user joined a room")
-            .clone()
+        match msg.message {
+            OutgoingMessageDTO::ServerShutdown(shutdown) => {
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Restart,
+                    description: Some(shutdown.reason),
+                }));
+                ctx.stop();
+            }
+            // removed from the room or the room was closed
+            OutgoingMessageDTO::Kicked(KickDTO {
+                room: Some(room), ..
+            }) => self.left(&room),
+            OutgoingMessageDTO::Kicked(KickDTO { room: None, reason }) => {
+                // leave for good instead of being suspended
+                if let Some(name) = self.name.as_ref() {
+                    for room in self.rooms.values() {
+                        room.do_send(LeaveRoomMessage {
+                            name: name.to_owned(),
+                            id: self.id,
+                        });
+                    }
+                }
+                self.rooms.clear();
+                self.active_room = None;
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Policy,
+                    description: Some(reason),
+                }));
+                ctx.stop();
+            }
+            _ => {}
+        }
     }
 }
 
@@ -153,6 +462,10 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
             ws::Message::Text(text) => {
                 debug!("Msg from >{:?}: '{}'", self.name, text);
 
+                if !self.check_limits(&text, ctx) {
+                    return;
+                }
+
                 if self.name.is_none() && !text.starts_with("/name") {
                     ctx.text(
                         system_message(
@@ -163,189 +476,857 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
                     return;
                 }
 
-                let m = text.trim();
-                // we check for /sss type of messages
-                if m.starts_with('/') {
-                    let v: Vec<&str> = m.splitn(2, ' ').collect();
-                    match v[0] {
-                        "/list" => {
-                            // Send ListRooms message to chat server and wait for
-                            // response
-                            self.server_addr
-                                .send(server::ListRooms)
-                                .into_actor(self)
-                                .then(|res, _, ctx| {
-                                    match res {
-                                        Ok(rooms) => {
-                                            for room in rooms {
-                                                ctx.text(room);
-                                            }
-                                        }
-                                        _ => error!("Something is wrong"),
+                self.handle_text(&text, ctx);
+            }
+            ws::Message::Binary(_) => error!("Unexpected binary"),
+            ws::Message::Close(reason) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            ws::Message::Continuation(_) => {
+                ctx.stop();
+            }
+            ws::Message::Nop => (),
+        }
+    }
+}
+
+impl WsChatSession {
+    /// Handles a chat message, roll or command of the client
+    fn handle_text(&mut self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        self.last_activity = Instant::now();
+        if self.idle {
+            self.idle = false;
+            self.send_presence();
+        }
+        let m = text.trim();
+        // we check for /sss type of messages
+        if m.starts_with('/') {
+            let v: Vec<&str> = m.splitn(2, ' ').collect();
+            match v[0] {
+                "/list" => {
+                    // Send ListRooms message to chat server and wait for
+                    // response
+                    self.server_addr
+                        .send(server::ListRooms)
+                        .into_actor(self)
+                        .then(|res, _, ctx| {
+                            match res {
+                                Ok(rooms) => {
+                                    for room in rooms {
+                                        ctx.text(room);
                                     }
-                                    fut::ready(())
-                                })
-                                .wait(ctx)
-                            // .wait(ctx) pauses all events in context,
-                            // so actor wont receive any new messages until it get list
-                            // of rooms back
-                        }
-                        "/join" => {
-                            if v.len() == 2 {
-                                if let Some(room_addr) = self.room_addr.as_ref() {
-                                    room_addr.do_send(LeaveRoomMessage {
-                                        name: self
-                                            .name
-                                            .as_ref()
-                                            .expect("Name must be provided here")
-                                            .to_owned(),
-                                        id: self.id,
-                                    });
                                 }
-
-                                let room_name = v[1].to_owned();
-
-                                self.server_addr
-                                    .send(server::RequestRoom {
-                                        name: room_name.clone(),
-                                    })
-                                    .into_actor(self)
-                                    .then(move |res, this, ctx| {
-                                        match res {
-                                            Ok(room_addr) => {
-                                                this.room_addr = Some(room_addr.clone());
-
-                                                room_addr.do_send(room::JoinRoomMessage {
-                                                    id: this.id,
-                                                    name: this.name.as_ref().unwrap().to_owned(),
-                                                    session_addr: ctx.address().into(),
-                                                });
-
-                                                ctx.text(
-                                                    OutgoingMessageDTO::TextMessage(
-                                                        TextMessageDTO::system(&format!(
-                                                            "You joined room {}",
-                                                            room_name
-                                                        )),
-                                                    )
-                                                    .to_json(),
-                                                );
-                                            }
-                                            _ => error!("Something is wrong"),
-                                        }
-
-                                        fut::ready(())
-                                    })
-                                    .wait(ctx)
-                            } else {
-                                ctx.text(
-                                    OutgoingMessageDTO::TextMessage(TextMessageDTO::system(
-                                        "!!! room name is required",
-                                    ))
-                                    .to_json(),
-                                );
+                                _ => error!("Something is wrong"),
                             }
-                        }
-                        "/name" => {
-                            if v.len() == 2 {
-                                self.name = Some(v[1].to_owned());
-                                ctx.text(
-                                    system_message(&format!(
-                                        "You are now known as: {}",
-                                        self.name.as_ref().unwrap()
-                                    ))
-                                    .to_json(),
-                                );
-                                if let Some(room_address) = self.room_addr.as_ref() {
-                                    room_address.do_send(room::NameChangedMessage);
-                                }
-                            } else {
-                                ctx.text(system_message("!!! name is required").to_json());
+                            fut::ready(())
+                        })
+                        .wait(ctx)
+                    // .wait(ctx) pauses all events in context,
+                    // so actor wont receive any new messages until it get list
+                    // of rooms back
+                }
+                "/join" => {
+                    if v.len() == 2 {
+                        self.join(v[1], ctx);
+                    } else {
+                        ctx.text(
+                            OutgoingMessageDTO::TextMessage(TextMessageDTO::system(
+                                "!!! room name is required",
+                            ))
+                            .to_json(),
+                        );
+                    }
+                }
+                "/leave" => {
+                    let room_name = v.get(1).map(|name| name.trim()).filter(|n| !n.is_empty());
+                    self.leave(room_name, ctx);
+                }
+                "/switch" => match v.get(1).map(|name| name.trim()) {
+                    Some(room_name) if !room_name.is_empty() => self.switch(room_name, ctx),
+                    _ => ctx.text(system_message("!!! room name is required").to_json()),
+                },
+                "/in" => match split_first_argument(v.get(1).unwrap_or(&"")) {
+                    Some((room_name, text)) if !text.is_empty() => {
+                        self.send_to_room(&room_name, text, ctx)
+                    }
+                    _ => ctx.text(
+                        system_message("!!! usage: /in \"GM notes\" <message or command>")
+                            .to_json(),
+                    ),
+                },
+                "/room" => {
+                    let arguments = split_arguments(v.get(1).unwrap_or(&""));
+                    let command = match arguments.first().map(String::as_str) {
+                        None => Ok(room::AccessCommand::Status),
+                        Some("lock") => match arguments.get(1) {
+                            Some(password) if !is_valid_password(password) => {
+                                Err("a password can't be empty or contain spaces")
                             }
+                            password => Ok(room::AccessCommand::Lock(password.cloned())),
+                        },
+                        Some("unlock") => Ok(room::AccessCommand::Unlock),
+                        Some("invite") => Ok(room::AccessCommand::Invite),
+                        Some(_) => Err("usage: /room [lock [password]|unlock|invite]"),
+                    };
+                    match (command, self.room_addr()) {
+                        (Ok(command), Some(room_addr)) => room_addr.do_send(room::AccessMessage {
+                            id: self.id,
+                            command,
+                        }),
+                        (Ok(_), None) => {
+                            ctx.text(system_message("!!! you have to join a room first").to_json())
+                        }
+                        (Err(error), _) => {
+                            ctx.text(system_message(&format!("!!! {}", error)).to_json())
+                        }
+                    }
+                }
+                "/kick" | "/ban" | "/unban" | "/mute" | "/unmute" | "/gm" => {
+                    let arguments = split_arguments(v.get(1).unwrap_or(&""));
+                    let command = moderation_command(v[0], &arguments);
+                    match (command, self.room_addr()) {
+                        (Ok(command), Some(room_addr)) => {
+                            room_addr.do_send(room::ModerationMessage {
+                                id: self.id,
+                                command,
+                            })
+                        }
+                        (Ok(_), None) => {
+                            ctx.text(system_message("!!! you have to join a room first").to_json())
+                        }
+                        (Err(error), _) => {
+                            ctx.text(system_message(&format!("!!! {}", error)).to_json())
+                        }
+                    }
+                }
+                "/say" | "/me" | "/ooc" => {
+                    let kind = match v[0] {
+                        "/me" => MessageKind::Emote,
+                        "/ooc" => MessageKind::Ooc,
+                        _ => MessageKind::Say,
+                    };
+                    match v.get(1).map(|text| text.trim()) {
+                        Some(text) if !text.is_empty() => self.send_chat(text, kind, None, ctx),
+                        _ => ctx.text(system_message("!!! text is required").to_json()),
+                    }
+                }
+                "/as" => match split_first_argument(v.get(1).unwrap_or(&"")) {
+                    Some((alias, text)) if text.starts_with('!') => {
+                        self.send_roll(text.to_owned(), Some(alias), ctx)
+                    }
+                    Some((alias, text)) if !text.is_empty() => {
+                        self.send_chat(text, MessageKind::Say, Some(alias), ctx)
+                    }
+                    _ => ctx.text(
+                        system_message("!!! usage: /as \"Vizier Kratt\" <text or roll>").to_json(),
+                    ),
+                },
+                "/alias" => {
+                    let arguments = split_arguments(v.get(1).unwrap_or(&""));
+                    let command = match (arguments.first().map(String::as_str), arguments.get(1)) {
+                        (None, _) => Ok(room::AliasCommand::List),
+                        (Some("add"), Some(alias)) => Ok(room::AliasCommand::Add(alias.to_owned())),
+                        (Some("remove"), Some(alias)) => {
+                            Ok(room::AliasCommand::Remove(alias.to_owned()))
+                        }
+                        _ => Err("usage: /alias [add|remove \"Vizier Kratt\"]"),
+                    };
+                    match (command, self.room_addr()) {
+                        (Ok(command), Some(room_addr)) => room_addr.do_send(room::AliasMessage {
+                            id: self.id,
+                            command,
+                        }),
+                        (Ok(_), None) => {
+                            ctx.text(system_message("!!! you have to join a room first").to_json())
+                        }
+                        (Err(error), _) => {
+                            ctx.text(system_message(&format!("!!! {}", error)).to_json())
+                        }
+                    }
+                }
+                "/topic" | "/description" => {
+                    let text = match v.get(1).map(|text| text.trim()) {
+                        None | Some("") => None,
+                        Some("clear") => Some(None),
+                        Some(text) => Some(Some(text.to_owned())),
+                    };
+                    let command = match (v[0], text) {
+                        (_, None) => room::BoardCommand::Show,
+                        ("/topic", Some(topic)) => room::BoardCommand::Topic(topic),
+                        (_, Some(description)) => room::BoardCommand::Description(description),
+                    };
+                    self.send_board_command(command, ctx);
+                }
+                "/pin" | "/unpin" => match v.get(1).and_then(|id| id.trim().parse::<u64>().ok()) {
+                    Some(id) if v[0] == "/pin" => {
+                        self.send_board_command(room::BoardCommand::Pin(id), ctx)
+                    }
+                    Some(id) => self.send_board_command(room::BoardCommand::Unpin(id), ctx),
+                    None => {
+                        ctx.text(system_message(&format!("!!! usage: {} <id>", v[0])).to_json())
+                    }
+                },
+                "/edit" | "/delete" => {
+                    let arguments: Vec<&str> =
+                        v.get(1).unwrap_or(&"").trim().splitn(2, ' ').collect();
+                    let message_id = arguments[0].parse::<u64>().ok();
+                    let text = arguments.get(1).map(|text| text.trim());
+                    match (message_id, text, self.room_addr()) {
+                        (_, _, None) => {
+                            ctx.text(system_message("!!! you have to join a room first").to_json())
+                        }
+                        (Some(message_id), _, Some(room_addr)) if v[0] == "/delete" => room_addr
+                            .do_send(room::DeleteMessage {
+                                id: self.id,
+                                message_id,
+                            }),
+                        (Some(message_id), Some(text), Some(room_addr)) if !text.is_empty() => {
+                            room_addr.do_send(room::EditMessage {
+                                id: self.id,
+                                message_id,
+                                text: text.to_owned(),
+                            })
                         }
                         _ => ctx.text(
-                            system_message(&format!("!!! unknown command: {:?}", m)).to_json(),
+                            system_message("!!! usage: /edit <id> <text> or /delete <id>")
+                                .to_json(),
                         ),
                     }
-                } else {
-                    if let Some(room_address) = self.room_addr.as_ref() {
-                        let sender = self.name.as_ref().unwrap();
-
-                        let msg = if m.starts_with('!') {
-                            let roll_result = get_results(&m[1..]);
-                            let sent_result;
-                            let sent_message;
-                            if is_hidden_roll(m) {
-                                sent_result = vec![roll_result.iter().sum()];
-                                sent_message = hide_roll_part(m);
-                            } else {
-                                sent_result = roll_result;
-                                sent_message = m.to_string();
-                            };
-                            TextMessageDTO::dice_result(&sent_message, &sent_result, &sender)
-                        } else {
-                            TextMessageDTO::chat(m, &sender)
-                        };
-
-                        room_address.do_send(room::ClientMessage {
+                }
+                "/name" => {
+                    if v.len() == 2 {
+                        self.name = Some(v[1].to_owned());
+                        ctx.text(
+                            system_message(&format!(
+                                "You are now known as: {}",
+                                self.name.as_ref().unwrap()
+                            ))
+                            .to_json(),
+                        );
+                        self.server_addr.do_send(server::Rename {
                             id: self.id,
-                            msg: msg,
+                            name: v[1].to_owned(),
                         });
+                        for room_address in self.rooms.values() {
+                            room_address.do_send(room::NameChangedMessage {
+                                id: self.id,
+                                name: v[1].to_owned(),
+                            });
+                        }
                     } else {
-                        ctx.text(
+                        ctx.text(system_message("!!! name is required").to_json());
+                    }
+                }
+                "/away" => {
+                    self.away = !self.away;
+                    let reply = if self.away {
+                        "You are away (/away again when you are back)"
+                    } else {
+                        "You are back"
+                    };
+                    ctx.text(system_message(reply).to_json());
+                    self.send_presence();
+                }
+                "/typing" => {
+                    if let Some(room_addr) = self.room_addr() {
+                        room_addr.do_send(room::TypingMessage { id: self.id });
+                    }
+                }
+                "/admin" => {
+                    let token = v.get(1).map(|token| token.trim());
+                    let valid = match (token, self.admin_token.as_ref()) {
+                        (Some(token), Some(expected)) => {
+                            admin::constant_time_eq(token.as_bytes(), expected.as_bytes())
+                        }
+                        _ => false,
+                    };
+                    if valid {
+                        self.admin = true;
+                        ctx.text(system_message("You are now an admin").to_json());
+                    } else {
+                        warn!("Failed admin login of session {}", self.id);
+                        ctx.text(system_message("!!! invalid admin token").to_json());
+                    }
+                }
+                "/sessions" => {
+                    if self.admin {
+                        self.list_sessions(ctx);
+                    } else {
+                        ctx.text(system_message("!!! only admins can list the sessions").to_json());
+                    }
+                }
+                "/combat" => {
+                    let command = match v.get(1).map(|c| c.trim()) {
+                        Some("start") => Some(room::CombatCommand::Start),
+                        Some("round") => Some(room::CombatCommand::NextRound),
+                        Some("end") => Some(room::CombatCommand::End),
+                        _ => None,
+                    };
+                    match command {
+                        Some(command) => self.send_combat_command(command, ctx),
+                        None => {
+                            ctx.text(system_message("!!! usage: /combat start|round|end").to_json())
+                        }
+                    }
+                }
+                "/next" => self.send_combat_command(room::CombatCommand::NextTurn, ctx),
+                "/stats" => match self.room_addr() {
+                    Some(room_address) => room_address.do_send(room::StatsMessage { id: self.id }),
+                    None => ctx.text(
+                        system_message("You have to join a room to see its statistics").to_json(),
+                    ),
+                },
+                "/export" => {
+                    let arguments = split_arguments(v.get(1).unwrap_or(&""));
+                    let format = arguments.first().map(|f| f.as_str()).unwrap_or("md");
+                    let session = match arguments.get(1) {
+                        Some(title) => SessionFilter::Titled(title.clone()),
+                        None => SessionFilter::All,
+                    };
+                    match (
+                        export::Format::from_name(format),
+                        arguments.len() <= 2,
+                        self.room_addr(),
+                    ) {
+                        (Some(format), true, Some(room_address)) => {
+                            self.export(format, session, room_address.clone(), ctx)
+                        }
+                        (Some(_), true, None) => ctx.text(
+                            system_message("You have to join a room to export its log").to_json(),
+                        ),
+                        _ => ctx.text(
+                            system_message("!!! usage: /export [md|html|json] [\"<session>\"]")
+                                .to_json(),
+                        ),
+                    }
+                }
+                "/history" => {
+                    let arguments = split_arguments(v.get(1).unwrap_or(&""));
+                    let session = match arguments.as_slice() {
+                        [] => Some(SessionFilter::Current),
+                        [title] => Some(SessionFilter::Titled(title.clone())),
+                        _ => None,
+                    };
+                    match (session, self.room_addr()) {
+                        (Some(session), Some(room_address)) => {
+                            self.replay_history(session, room_address.clone(), ctx)
+                        }
+                        (Some(_), None) => ctx.text(
+                            system_message("You have to join a room to see its history").to_json(),
+                        ),
+                        (None, _) => ctx
+                            .text(system_message("!!! usage: /history [\"<session>\"]").to_json()),
+                    }
+                }
+                "/session" => {
+                    let arguments = split_arguments(v.get(1).unwrap_or(&""));
+                    match arguments.as_slice() {
+                        [start, title] if start == "start" => {
+                            self.send_bookmark(BookmarkKind::Session, title, ctx)
+                        }
+                        _ => ctx.text(
+                            system_message("!!! usage: /session start \"<title>\"").to_json(),
+                        ),
+                    }
+                }
+                "/scene" => {
+                    let arguments = split_arguments(v.get(1).unwrap_or(&""));
+                    match arguments.as_slice() {
+                        [title] => self.send_bookmark(BookmarkKind::Scene, title, ctx),
+                        _ => ctx.text(system_message("!!! usage: /scene \"<title>\"").to_json()),
+                    }
+                }
+                "/odds" => {
+                    let arguments = v.get(1).unwrap_or(&"");
+                    let (roll, target) = match arguments.split_once(" vs ") {
+                        Some((roll, target)) => (roll, Some(target.trim())),
+                        None => (*arguments, None),
+                    };
+                    let odds = match target.map(|t| t.parse::<i32>()) {
+                        Some(Err(_)) => Err("invalid target number".to_string()),
+                        Some(Ok(target)) => odds::calculate(roll, Some(target), &self.dice_limits),
+                        None => odds::calculate(roll, None, &self.dice_limits),
+                    };
+                    match odds {
+                        Ok(odds) => ctx.text(system_message(&odds::describe(&odds)).to_json()),
+                        Err(error) => ctx.text(
+                            system_message(&format!("!!! {} (usage: /odds [10] vs 12)", error))
+                                .to_json(),
+                        ),
+                    }
+                }
+                "/repeat" => {
+                    let arguments: Vec<&str> = v.get(1).unwrap_or(&"").splitn(2, ' ').collect();
+                    match (arguments[0].parse::<u32>(), arguments.get(1)) {
+                        (Ok(repetitions), Some(request)) if request.starts_with('!') => {
+                            self.send_roll(format!("!{}x{}", repetitions, &request[1..]), None, ctx)
+                        }
+                        _ => ctx.text(
                             system_message(
-                                "You have to join a room before sending messages (i.e. /join Main)",
+                                "!!! usage: /repeat <count> <roll>, i.e. /repeat 5 !![8]",
                             )
                             .to_json(),
-                        )
+                        ),
                     }
                 }
+                "/effect" => match (
+                    effects::parse_effect_command(v.get(1).unwrap_or(&"")),
+                    self.room_addr(),
+                ) {
+                    (Ok(command), Some(room_address)) => {
+                        room_address.do_send(room::EffectMessage {
+                            id: self.id,
+                            command,
+                        })
+                    }
+                    (Ok(_), None) => ctx.text(
+                        system_message("You have to join a room before adding effects").to_json(),
+                    ),
+                    (Err(error), _) => {
+                        ctx.text(system_message(&format!("!!! {}", error)).to_json())
+                    }
+                },
+                _ => ctx.text(system_message(&format!("!!! unknown command: {:?}", m)).to_json()),
             }
-            ws::Message::Binary(_) => error!("Unexpected binary"),
-            ws::Message::Close(reason) => {
-                ctx.close(reason);
-                ctx.stop();
+        } else {
+            if m.starts_with('!') {
+                self.send_roll(m.to_owned(), None, ctx);
+            } else {
+                self.send_chat(m, MessageKind::Say, None, ctx);
             }
-            ws::Message::Continuation(_) => {
-                ctx.stop();
+        }
+    }
+
+    /// The room messages and commands are sent to
+    fn room_addr(&self) -> Option<&Addr<room::ChatRoom>> {
+        self.rooms.get(self.active_room.as_ref()?)
+    }
+
+    /// Joins a room, i.e. `Main` or `Main password:secret`, in addition to the
+    /// joined ones. Messages are sent to the room joined last.
+    fn join(&mut self, input: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let (room_name, credential) = split_credential(input);
+        let room_name = room_name.to_owned();
+        if self.rooms.contains_key(&room_name) {
+            return self.switch(&room_name, ctx);
+        }
+        let credential = credential.or_else(|| match self.invite.as_ref() {
+            Some((room, token)) if *room == room_name => Some(Credential::Invite(token.clone())),
+            _ => None,
+        });
+
+        self.server_addr
+            .send(server::RequestRoom {
+                name: room_name.clone(),
+            })
+            .into_actor(self)
+            .then(move |res, this, ctx| {
+                match res {
+                    Ok(room_addr) => room_addr
+                        .send(room::JoinRoomMessage {
+                            id: this.id,
+                            name: this.name.as_ref().unwrap().to_owned(),
+                            session_addr: ctx.address(),
+                            remote_addr: this.remote_addr.clone(),
+                            credential,
+                        })
+                        .into_actor(this)
+                        .then(move |res, this, ctx| {
+                            match res {
+                                Ok(Ok(())) => this.joined(room_name, room_addr, ctx),
+                                Ok(Err(error)) => {
+                                    ctx.text(system_message(&format!("!!! {}", error)).to_json())
+                                }
+                                _ => error!("Something is wrong"),
+                            }
+                            fut::ready(())
+                        })
+                        .wait(ctx),
+                    _ => error!("Something is wrong"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx)
+    }
+
+    fn joined(
+        &mut self,
+        room_name: String,
+        room_addr: Addr<room::ChatRoom>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        if self.presence() != Presence::Online {
+            room_addr.do_send(room::PresenceMessage {
+                id: self.id,
+                presence: self.presence(),
+            });
+        }
+        self.rooms.insert(room_name.clone(), room_addr);
+        self.active_room = Some(room_name.clone());
+        ctx.text(system_message(&format!("You joined room {}", room_name)).to_json());
+        self.server_addr.do_send(server::JoinedRoom {
+            id: self.id,
+            name: room_name,
+        });
+    }
+
+    fn presence(&self) -> Presence {
+        Presence::of_session(self.away, self.idle)
+    }
+
+    /// Tells all joined rooms that the session became idle, away or is back
+    fn send_presence(&self) {
+        for room_addr in self.rooms.values() {
+            room_addr.do_send(room::PresenceMessage {
+                id: self.id,
+                presence: self.presence(),
+            });
+        }
+    }
+
+    /// Sends messages and commands to another joined room
+    fn switch(&mut self, room_name: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        if !self.rooms.contains_key(room_name) {
+            return ctx
+                .text(system_message(&format!("!!! you are not in room {}", room_name)).to_json());
+        }
+        self.active_room = Some(room_name.to_owned());
+        ctx.text(system_message(&format!("You are now talking in {}", room_name)).to_json());
+    }
+
+    /// Leaves a joined room, the current one if no name is given
+    fn leave(&mut self, room_name: Option<&str>, ctx: &mut ws::WebsocketContext<Self>) {
+        let room_name = match room_name.or(self.active_room.as_deref()) {
+            Some(room_name) => room_name.to_owned(),
+            None => {
+                return ctx.text(system_message("!!! you are not in a room").to_json());
+            }
+        };
+        match self.rooms.get(&room_name) {
+            Some(room_addr) => room_addr.do_send(LeaveRoomMessage {
+                name: self
+                    .name
+                    .as_ref()
+                    .expect("Name must be provided here")
+                    .to_owned(),
+                id: self.id,
+            }),
+            None => {
+                return ctx.text(
+                    system_message(&format!("!!! you are not in room {}", room_name)).to_json(),
+                );
             }
-            ws::Message::Nop => (),
+        }
+        self.left(&room_name);
+        ctx.text(system_message(&format!("You left room {}", room_name)).to_json());
+    }
+
+    /// Forgets a room which was left, the first of the other rooms becomes the
+    /// current one
+    fn left(&mut self, room_name: &str) {
+        self.rooms.remove(room_name);
+        if self.active_room.as_deref() == Some(room_name) {
+            self.active_room = self.rooms.keys().next().cloned();
+        }
+        self.server_addr.do_send(server::LeftRoom {
+            id: self.id,
+            name: room_name.to_owned(),
+        });
+    }
+
+    /// Handles a single message or command for a room other than the current
+    /// one, i.e. `/in "GM notes" !![9] Perception`
+    fn send_to_room(&mut self, room_name: &str, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        if !self.rooms.contains_key(room_name) {
+            return ctx
+                .text(system_message(&format!("!!! you are not in room {}", room_name)).to_json());
+        }
+        let command = text.split(' ').next().unwrap_or("");
+        if ["/in", "/join", "/leave", "/switch"].contains(&command) {
+            return ctx.text(
+                system_message(&format!("!!! {} can't be used with /in", command)).to_json(),
+            );
+        }
+        let active_room = self.active_room.replace(room_name.to_owned());
+        self.handle_text(text, ctx);
+        self.active_room = active_room;
+    }
+
+    fn send_chat(
+        &self,
+        text: &str,
+        kind: MessageKind,
+        alias: Option<String>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        if let Some(room_address) = self.room_addr() {
+            let sender = self.name.as_ref().unwrap();
+
+            room_address.do_send(room::ClientMessage {
+                id: self.id,
+                msg: TextMessageDTO::chat(text, sender)
+                    .with_kind(kind)
+                    .with_alias(alias),
+            });
+        } else {
+            ctx.text(
+                system_message("You have to join a room before sending messages (i.e. /join Main)")
+                    .to_json(),
+            )
+        }
+    }
+
+    fn send_board_command(
+        &self,
+        command: room::BoardCommand,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        match self.room_addr() {
+            Some(room_addr) => room_addr.do_send(room::BoardMessage {
+                id: self.id,
+                command,
+            }),
+            None => ctx.text(system_message("!!! you have to join a room first").to_json()),
+        }
+    }
+
+    /// Checks the length and the rate limits of a message from the client
+    fn check_limits(&mut self, text: &str, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        if text.len() > self.max_message_length {
+            self.reject(
+                ErrorCode::MessageTooLong,
+                &format!(
+                    "a message can have at most {} characters",
+                    self.max_message_length
+                ),
+                ctx,
+            );
+            return false;
+        }
+
+        let action = Action::of(text);
+        let now = Instant::now();
+        let mut allowed = self.buckets.try_take(action, now);
+        if let Some(remote_addr) = self.remote_addr.as_ref() {
+            allowed = allowed && self.address_limiter.try_take(remote_addr, action, now);
+        }
+        // typing notifications are only dropped, they are sent repeatedly
+        if !allowed && action != Action::Typing {
+            self.reject(
+                ErrorCode::RateLimited,
+                "too many messages, please slow down",
+                ctx,
+            );
+        }
+        allowed
+    }
+
+    /// Sends an error and closes the session after too many violations
+    fn reject(&mut self, code: ErrorCode, message: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.text(
+            OutgoingMessageDTO::Error(ErrorDTO {
+                code,
+                message: message.to_string(),
+            })
+            .to_json(),
+        );
+        if !self.strikes.try_take(Instant::now()) {
+            warn!(
+                "Disconnecting session {} from {:?} for repeated violations",
+                self.id, self.remote_addr
+            );
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some("too many violations".to_string()),
+            }));
+            ctx.stop();
+        }
+    }
+
+    /// Continues a disconnected session in its room
+    fn resume(&mut self, resumed: server::ResumedSession, ctx: &mut ws::WebsocketContext<Self>) {
+        self.name = resumed.name;
+        self.active_room = resumed.rooms.last().map(|(name, _)| name.clone());
+        self.rooms = resumed.rooms.into_iter().collect();
+
+        if let Some(name) = self.name.as_ref() {
+            for room_addr in self.rooms.values() {
+                room_addr.do_send(room::ResumeMember {
+                    id: self.id,
+                    name: name.clone(),
+                    session_addr: ctx.address(),
+                    remote_addr: self.remote_addr.clone(),
+                });
+            }
+        }
+        if self.presence() != Presence::Online {
+            self.send_presence();
+        }
+        ctx.text(system_message("Your session was resumed").to_json());
+    }
+
+    fn list_sessions(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        self.server_addr
+            .send(server::ListSessions)
+            .into_actor(self)
+            .then(|res, _, ctx| {
+                match res {
+                    Ok(sessions) => {
+                        let lines: Vec<String> =
+                            sessions.iter().map(server::SessionInfo::summary).collect();
+                        ctx.text(
+                            system_message(&format!(
+                                "{} session(s):<br>{}",
+                                sessions.len(),
+                                lines.join("<br>")
+                            ))
+                            .to_json(),
+                        );
+                    }
+                    _ => error!("Something is wrong"),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn send_roll(&self, text: String, alias: Option<String>, ctx: &mut ws::WebsocketContext<Self>) {
+        match self.room_addr() {
+            Some(room_address) => room_address.do_send(room::RollMessage {
+                id: self.id,
+                sender: self.name.as_ref().unwrap().to_owned(),
+                alias,
+                text,
+            }),
+            None => ctx.text(
+                system_message("You have to join a room before sending messages (i.e. /join Main)")
+                    .to_json(),
+            ),
+        }
+    }
+
+    fn export(
+        &self,
+        format: export::Format,
+        session: SessionFilter,
+        room_address: Addr<room::ChatRoom>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        room_address
+            .send(room::LogMessage {
+                viewer: Some(self.id),
+                credential: None,
+                since: None,
+                session,
+            })
+            .into_actor(self)
+            .then(move |res, _, ctx| {
+                match res {
+                    Ok(Ok(log)) => ctx.text(
+                        OutgoingMessageDTO::Export(ExportDTO {
+                            file_name: format!("{}.{}", log.room_name, format.extension()),
+                            content_type: format.content_type().to_owned(),
+                            content: export::render(&log.room_name, &log.messages, format),
+                            room_name: log.room_name,
+                        })
+                        .to_json(),
+                    ),
+                    _ => error!("Something is wrong"),
+                }
+                fut::ready(())
+            })
+            .wait(ctx)
+    }
+
+    /// Sends earlier messages of the room to this client only
+    fn replay_history(
+        &self,
+        session: SessionFilter,
+        room_address: Addr<room::ChatRoom>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let title = match &session {
+            SessionFilter::Titled(title) => Some(title.clone()),
+            _ => None,
+        };
+        room_address
+            .send(room::LogMessage {
+                viewer: Some(self.id),
+                credential: None,
+                since: None,
+                session,
+            })
+            .into_actor(self)
+            .then(move |res, _, ctx| {
+                match res {
+                    Ok(Ok(log)) => ctx.text(
+                        OutgoingMessageDTO::History(HistoryDTO {
+                            room_name: log.room_name,
+                            session: title,
+                            messages: log.messages,
+                        })
+                        .to_json(),
+                    ),
+                    _ => error!("Something is wrong"),
+                }
+                fut::ready(())
+            })
+            .wait(ctx)
+    }
+
+    fn send_bookmark(&self, kind: BookmarkKind, title: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        match self.room_addr() {
+            Some(room_address) => room_address.do_send(room::BookmarkMessage {
+                id: self.id,
+                kind,
+                title: title.to_owned(),
+            }),
+            None => ctx.text(
+                system_message("You have to join a room before starting a session").to_json(),
+            ),
+        }
+    }
+
+    fn send_combat_command(
+        &self,
+        command: room::CombatCommand,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        match self.room_addr() {
+            Some(room_address) => room_address.do_send(room::CombatMessage {
+                id: self.id,
+                command,
+            }),
+            None => ctx
+                .text(system_message("You have to join a room before managing a combat").to_json()),
         }
     }
-}
 
-impl WsChatSession {
     /// helper method that sends ping to client every second.
     ///
     /// also this method checks heartbeats from client
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+        ctx.run_interval(self.heartbeat_interval, |act, ctx| {
             // check client heartbeats
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+            if Instant::now().duration_since(act.hb) > act.client_timeout {
                 // heartbeat timed out
-                println!("Websocket Client heartbeat failed, disconnecting!");
-
-                // notify chat server
-                act.server_addr.do_send(server::Disconnect { id: act.id });
-
-                if let Some(room) = act.room_addr.as_ref() {
-                    room.do_send(LeaveRoomMessage {
-                        name: act
-                            .name
-                            .clone()
-                            .unwrap_or_else(|| "<<unknown>>".to_string())
-                            .to_owned(),
-                        id: act.id,
-                    });
-                }
+                warn!("Websocket Client heartbeat failed, disconnecting!");
+                metrics::record_heartbeat_timeout();
 
-                // stop actor
+                // stop actor, which notifies chat server and room
                 ctx.stop();
 
                 // don't try to send a ping
                 return;
             }
 
+            if !act.idle && Instant::now().duration_since(act.last_activity) > act.idle_timeout {
+                act.idle = true;
+                act.send_presence();
+            }
+
             ctx.ping(b"");
         });
     }
@@ -355,20 +1336,81 @@ fn system_message(test: &str) -> OutgoingMessageDTO {
     OutgoingMessageDTO::TextMessage(TextMessageDTO::system(test))
 }
 
+/// Parses `/kick <name>`, `/ban <name> [duration]` and the like
+fn moderation_command(
+    command: &str,
+    arguments: &[String],
+) -> Result<room::ModerationCommand, String> {
+    let name = match arguments.first() {
+        Some(name) => name.to_owned(),
+        None => return Err(format!("usage: {} <name>", command)),
+    };
+    let duration =
+        match arguments.get(1) {
+            Some(duration) => Some(moderation::parse_duration(duration).ok_or_else(|| {
+                format!("invalid duration '{}' (i.e. 30s, 10m, 2h or 1d)", duration)
+            })?),
+            None => None,
+        };
+    Ok(match command {
+        "/kick" => room::ModerationCommand::Kick(name),
+        "/ban" => room::ModerationCommand::Ban(name, duration),
+        "/unban" => room::ModerationCommand::Unban(name),
+        "/mute" => room::ModerationCommand::Mute(name, duration),
+        "/unmute" => room::ModerationCommand::Unmute(name),
+        _ => room::ModerationCommand::Gm(name),
+    })
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init();
+    let config = Config::load(Cli::parse())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(&config.log_level))
+        .init();
 
-    let port = env::args().skip(1).next().unwrap_or_else(|| "8080".into());
-    info!("Starting on port {}", port);
+    info!("Starting on {}:{}", config.bind, config.port);
+
+    let storage = match config.storage_dir.as_ref() {
+        Some(dir) => {
+            info!("Storing room state in {}", dir.display());
+            Some(Arc::new(storage::Storage::new(dir.clone())?))
+        }
+        None => None,
+    };
 
     // Start chat server actor
-    let server = server::ChatServer::new().start();
+    let server = server::ChatServer::new(
+        &config.default_rooms,
+        RoomSettings {
+            storage,
+            history_size: config.history_size,
+            resume_grace_period: config.resume_grace_period(),
+            dice_limits: config.dice_limits,
+        },
+    )
+    .start();
+
+    let address = (config.bind.clone(), config.port);
+    let tls_config = match (config.tls_cert.as_ref(), config.tls_key.as_ref()) {
+        (Some(cert), Some(key)) => Some(tls::load_config(cert, key)?),
+        _ => None,
+    };
+    let redirect_port = config.http_redirect_port;
+    let https_port = config.port;
+    let redirect_address = (config.bind.clone(), redirect_port.unwrap_or_default());
+    let shutdown_eta = config.shutdown_eta;
+    let chat_server = server.clone();
+    // shared by all workers
+    let address_limiter = web::Data::new(AddressLimiter::new(config.rate_limits.clone()));
 
     // Create Http server with websocket support
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .data(server.clone())
+            .data(config.clone())
+            .app_data(address_limiter.clone())
             // redirect to websocket.html
             .service(web::resource("/").route(web::get().to(|| {
                 HttpResponse::Found()
@@ -377,10 +1419,51 @@ async fn main() -> std::io::Result<()> {
             })))
             // websocket
             .service(web::resource("/ws/").to(chat_route))
+            .service(web::resource("/odds").route(web::get().to(odds_route)))
+            .service(web::resource("/healthz").route(web::get().to(health_route)))
+            .service(web::resource("/readyz").route(web::get().to(ready_route)))
+            .service(web::resource("/metrics").route(web::get().to(metrics_route)))
+            .service(web::resource("/rooms/{name}/log").route(web::get().to(log_route)))
+            .service(web::scope("/admin").configure(admin::routes))
             // static resources
-            .service(fs::Files::new("/static/", "static/"))
-    })
-    .bind(format!("127.0.0.1:{}", port))?
-    .run()
-    .await
+            .service(fs::Files::new("/static/", config.static_dir.clone()))
+    });
+    // signals are handled by `shutdown` to notify the clients first
+    let server = match tls_config {
+        Some(tls_config) => {
+            info!("Serving https");
+            server.bind_rustls(address, tls_config)?
+        }
+        None => server.bind(address)?,
+    }
+    .disable_signals()
+    .run();
+
+    let redirect = match redirect_port {
+        Some(port) => {
+            info!("Redirecting http on port {} to https", port);
+            let redirect = HttpServer::new(move || {
+                App::new().default_service(web::to(move |req: HttpRequest| {
+                    future::ready(tls::redirect_to_https(&req, https_port))
+                }))
+            })
+            .bind(redirect_address)?
+            .disable_signals()
+            .run();
+            Some(redirect)
+        }
+        None => None,
+    };
+
+    let mut http_servers = vec![server.clone()];
+    http_servers.extend(redirect.clone());
+    actix_web::rt::spawn(async move {
+        shutdown::wait_for_signal().await;
+        shutdown::shutdown(chat_server, http_servers, shutdown_eta).await;
+    });
+
+    match redirect {
+        Some(redirect) => futures::try_join!(server, redirect).map(|_| ()),
+        None => server.await,
+    }
 }
@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use chrono::serde::ts_milliseconds;
 use chrono::DateTime;
 use chrono::Utc;
@@ -7,21 +9,305 @@ use serde::{Deserialize, Serialize};
 pub enum OutgoingMessageDTO {
     TextMessage(TextMessageDTO),
     RoomState(RoomStateMessageDTO),
+    CombatState(CombatStateDTO),
+    Stats(StatsDTO),
+    Export(ExportDTO),
+    History(HistoryDTO),
+    ServerShutdown(ServerShutdownDTO),
+    Session(SessionDTO),
+    Kicked(KickDTO),
+    Error(ErrorDTO),
+    MessageEdited(TextMessageDTO),
+    MessageDeleted(MessageDeletedDTO),
+    /// Changed presence of a member, never kept in the history
+    Presence(MemberDTO),
+    /// Changes of the member list, the full list is only sent on join
+    MemberJoined(MemberDTO),
+    MemberLeft(MemberLeftDTO),
+    MemberRenamed(MemberRenamedDTO),
+}
+
+/// A message of a room, tagged with the name of the room next to the message
+/// (i.e. `{"room": "Main", "TextMessage": {...}}`)
+#[derive(Serialize, Debug)]
+pub struct RoomEnvelopeDTO<'a> {
+    pub room: &'a str,
+    #[serde(flatten)]
+    pub message: &'a OutgoingMessageDTO,
+}
+
+impl RoomEnvelopeDTO<'_> {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RoomStateMessageDTO {
     pub room_name: String,
-    pub members: Vec<String>,
+    pub members: Vec<MemberDTO>,
+    pub effects: Vec<EffectDTO>,
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    pub pins: Vec<TextMessageDTO>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Presence {
+    #[default]
+    Online,
+    /// No messages for a while
+    Idle,
+    /// Set with `/away`
+    Away,
+    /// Writing a message right now
+    Typing,
+}
+
+impl Presence {
+    /// The presence a session tells its rooms, `/away` wins over idle
+    pub fn of_session(away: bool, idle: bool) -> Presence {
+        if away {
+            Presence::Away
+        } else if idle {
+            Presence::Idle
+        } else {
+            Presence::Online
+        }
+    }
+
+    /// The presence the others see, typing wins over the told one
+    pub fn shown(self, typing: bool) -> Presence {
+        if typing {
+            Presence::Typing
+        } else {
+            self
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemberDTO {
+    /// Session id of the member, names can be taken several times
+    pub id: usize,
+    pub name: String,
+    pub presence: Presence,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemberLeftDTO {
+    pub id: usize,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemberRenamedDTO {
+    pub id: usize,
+    pub old_name: String,
+    pub new_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EffectDTO {
+    pub target: String,
+    pub name: String,
+    /// Added to the step of step rolls
+    pub modifier: i32,
+    /// Remaining rounds, `None` lasts until removed
+    pub rounds: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CombatStateDTO {
+    pub in_combat: bool,
+    pub round: u32,
+    /// Index of the active combatant in `order`
+    pub active: Option<usize>,
+    pub order: Vec<InitiativeDTO>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InitiativeDTO {
+    pub name: String,
+    pub sub_name: Option<String>,
+    pub result: i32,
+    pub description: String,
+}
+
+/// Result of a single term of a roll, i.e. `4d6kh3` or `2`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TermResultDTO {
+    pub expression: String,
+    pub total: i32,
+    pub dice: Vec<DieResultDTO>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DieResultDTO {
+    pub sides: u32,
+    /// The face and, if the die exploded, all faces rolled afterwards
+    pub rolls: Vec<u32>,
+    /// The first face if the die was rerolled
+    pub rerolled: Option<u32>,
+    /// Whether the die was dropped by keep/drop modifiers
+    pub dropped: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatsDTO {
+    pub room_name: String,
+    pub players: Vec<PlayerStatsDTO>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlayerStatsDTO {
+    pub name: String,
+    pub rolls: u64,
+    pub average_result: Option<f64>,
+    /// Average of the expected results of the same rolls
+    pub average_expected: Option<f64>,
+    pub explosions: u64,
+    pub rule_of_ones: u64,
+    /// Die size -> face -> how often it was rolled
+    pub faces: BTreeMap<u32, BTreeMap<u32, u64>>,
+}
+
+/// Marks the start of a session or scene in the room history
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BookmarkDTO {
+    pub kind: BookmarkKind,
+    pub title: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum BookmarkKind {
+    Session,
+    Scene,
+}
+
+/// Earlier messages of a room, replayed to a single client
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryDTO {
+    pub room_name: String,
+    pub session: Option<String>,
+    pub messages: Vec<TextMessageDTO>,
+}
+
+/// Sent on connect, the token resumes the session after a reconnect
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionDTO {
+    pub token: String,
+    /// Whether an earlier session was resumed
+    pub resumed: bool,
+}
+
+/// Sent to a session which was removed from a room or from the server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KickDTO {
+    /// The room the session was removed from, `None` if it was disconnected
+    pub room: Option<String>,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageDeletedDTO {
+    pub id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    RateLimited,
+    MessageTooLong,
+    TooManyDice,
+    InvalidRoll,
+    Muted,
+}
+
+/// A rejected request of the client
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorDTO {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+/// Sent to all clients before the server goes down
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerShutdownDTO {
+    pub reason: String,
+    /// Seconds until the server is expected to be back
+    pub eta: Option<u64>,
+}
+
+/// Exported chat log of a room, to be downloaded by the client
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportDTO {
+    pub room_name: String,
+    pub file_name: String,
+    pub content_type: String,
+    pub content: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OddsDTO {
+    pub roll: String,
+    pub target: Option<i32>,
+    pub mean: f64,
+    pub percentiles: Vec<PercentileDTO>,
+    pub success_levels: Vec<SuccessLevelDTO>,
+    /// Probability of each possible result
+    pub distribution: Vec<(i32, f64)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PercentileDTO {
+    pub percentile: u32,
+    pub value: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SuccessLevelDTO {
+    /// Number of successes, 0 is a failure
+    pub successes: u32,
+    pub probability: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum MessageKind {
+    /// In character speech
+    #[default]
+    Say,
+    /// An action of the character, i.e. `/me draws his sword`
+    Emote,
+    /// Out of character chatter
+    Ooc,
+    Roll,
+    System,
+    Bookmark,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TextMessageDTO {
+    /// Assigned by the room, `None` for messages which are not kept
+    #[serde(default)]
+    id: Option<u64>,
+    #[serde(default)]
+    kind: MessageKind,
     message: String,
+    /// The real sender
     name: Option<String>,
+    /// Name a game master speaks or rolls as, shown instead of the sender
+    #[serde(default)]
+    alias: Option<String>,
     dice_results: Option<Vec<i32>>,
+    /// Results of a roll that was repeated multiple times (i.e. `!5x[8]`)
+    repeated_dice_results: Option<Vec<Vec<i32>>>,
+    /// Single dice of each roll, one entry per repetition
+    breakdown: Option<Vec<Vec<TermResultDTO>>>,
+    bookmark: Option<BookmarkDTO>,
     #[serde(with = "ts_milliseconds")]
     time: DateTime<Utc>,
+    #[serde(default)]
+    edited: bool,
 }
 
 impl OutgoingMessageDTO {
@@ -33,28 +319,230 @@ impl OutgoingMessageDTO {
 impl TextMessageDTO {
     pub fn dice_result(message: &str, dice_results: &Vec<i32>, sender: &str) -> Self {
         TextMessageDTO {
+            id: None,
+            kind: MessageKind::Roll,
             message: message.to_owned(),
             name: Some(sender.to_owned()),
+            alias: None,
             dice_results: Some(dice_results.clone()),
+            repeated_dice_results: None,
+            breakdown: None,
+            bookmark: None,
             time: Utc::now(),
+            edited: false,
+        }
+    }
+
+    pub fn repeated_dice_result(message: &str, dice_results: &[Vec<i32>], sender: &str) -> Self {
+        TextMessageDTO {
+            id: None,
+            kind: MessageKind::Roll,
+            message: message.to_owned(),
+            name: Some(sender.to_owned()),
+            alias: None,
+            dice_results: None,
+            repeated_dice_results: Some(dice_results.to_vec()),
+            breakdown: None,
+            bookmark: None,
+            time: Utc::now(),
+            edited: false,
         }
     }
 
     pub fn chat(message: &str, sender: &str) -> Self {
         TextMessageDTO {
+            id: None,
+            kind: MessageKind::Say,
             message: message.to_owned(),
             name: Some(sender.to_owned()),
+            alias: None,
             dice_results: None,
+            repeated_dice_results: None,
+            breakdown: None,
+            bookmark: None,
             time: Utc::now(),
+            edited: false,
         }
     }
 
     pub fn system(message: &str) -> Self {
         TextMessageDTO {
+            id: None,
+            kind: MessageKind::System,
             message: message.to_owned(),
             name: None,
+            alias: None,
+            dice_results: None,
+            repeated_dice_results: None,
+            breakdown: None,
+            bookmark: None,
+            time: Utc::now(),
+            edited: false,
+        }
+    }
+
+    pub fn bookmark_message(kind: BookmarkKind, title: &str) -> Self {
+        TextMessageDTO {
+            id: None,
+            kind: MessageKind::Bookmark,
+            message: title.to_owned(),
+            name: None,
+            alias: None,
             dice_results: None,
+            repeated_dice_results: None,
+            breakdown: None,
+            bookmark: Some(BookmarkDTO {
+                kind,
+                title: title.to_owned(),
+            }),
             time: Utc::now(),
+            edited: false,
         }
     }
+
+    pub fn with_breakdown(mut self, breakdown: Vec<Vec<TermResultDTO>>) -> Self {
+        self.breakdown = Some(breakdown);
+        self
+    }
+
+    pub fn with_kind(mut self, kind: MessageKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    pub fn with_alias(mut self, alias: Option<String>) -> Self {
+        self.alias = alias;
+        self
+    }
+
+    pub fn with_id(mut self, id: u64) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// Replaces the text, the dice results of rolls must not be edited
+    pub fn edit(&mut self, message: &str) {
+        self.message = message.to_owned();
+        self.edited = true;
+    }
+
+    pub fn id(&self) -> Option<u64> {
+        self.id
+    }
+
+    pub fn kind(&self) -> MessageKind {
+        self.kind
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn alias(&self) -> Option<&str> {
+        self.alias.as_deref()
+    }
+
+    /// The alias if there is one, otherwise the sender
+    pub fn display_name(&self) -> Option<&str> {
+        self.alias().or_else(|| self.name())
+    }
+
+    pub fn dice_results(&self) -> Option<&[i32]> {
+        self.dice_results.as_deref()
+    }
+
+    pub fn repeated_dice_results(&self) -> Option<&[Vec<i32>]> {
+        self.repeated_dice_results.as_deref()
+    }
+
+    pub fn breakdown(&self) -> Option<&[Vec<TermResultDTO>]> {
+        self.breakdown.as_deref()
+    }
+
+    pub fn bookmark(&self) -> Option<&BookmarkDTO> {
+        self.bookmark.as_ref()
+    }
+
+    pub fn time(&self) -> DateTime<Utc> {
+        self.time
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_room_envelope() {
+        let message = OutgoingMessageDTO::MessageDeleted(MessageDeletedDTO { id: 3 });
+        let json = RoomEnvelopeDTO {
+            room: "GM notes",
+            message: &message,
+        }
+        .to_json();
+        assert_eq!(r#"{"room":"GM notes","MessageDeleted":{"id":3}}"#, json);
+    }
+
+    #[test]
+    fn test_member_deltas() {
+        let member = MemberDTO {
+            id: 7,
+            name: "Thorin".to_string(),
+            presence: Presence::Online,
+        };
+        let state = OutgoingMessageDTO::RoomState(RoomStateMessageDTO {
+            room_name: "Main".to_string(),
+            members: vec![member.clone()],
+            effects: vec![],
+            topic: None,
+            description: None,
+            pins: vec![],
+        });
+        assert!(state
+            .to_json()
+            .contains(r#""members":[{"id":7,"name":"Thorin","presence":"Online"}]"#));
+
+        assert_eq!(
+            r#"{"MemberJoined":{"id":7,"name":"Thorin","presence":"Online"}}"#,
+            OutgoingMessageDTO::MemberJoined(member.clone()).to_json()
+        );
+        assert_eq!(
+            r#"{"Presence":{"id":7,"name":"Thorin","presence":"Typing"}}"#,
+            OutgoingMessageDTO::Presence(MemberDTO {
+                presence: Presence::Typing,
+                ..member
+            })
+            .to_json()
+        );
+        assert_eq!(
+            r#"{"MemberRenamed":{"id":7,"old_name":"Thorin","new_name":"Gimli"}}"#,
+            OutgoingMessageDTO::MemberRenamed(MemberRenamedDTO {
+                id: 7,
+                old_name: "Thorin".to_string(),
+                new_name: "Gimli".to_string(),
+            })
+            .to_json()
+        );
+        assert_eq!(
+            r#"{"MemberLeft":{"id":7,"name":"Gimli"}}"#,
+            OutgoingMessageDTO::MemberLeft(MemberLeftDTO {
+                id: 7,
+                name: "Gimli".to_string(),
+            })
+            .to_json()
+        );
+    }
+
+    #[test]
+    fn test_presence() {
+        assert_eq!(Presence::Online, Presence::of_session(false, false));
+        assert_eq!(Presence::Idle, Presence::of_session(false, true));
+        assert_eq!(Presence::Away, Presence::of_session(true, true));
+        assert_eq!(Presence::Typing, Presence::Away.shown(true));
+        assert_eq!(Presence::Idle, Presence::Idle.shown(false));
+    }
 }
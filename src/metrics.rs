@@ -0,0 +1,161 @@
+//! Metrics in the Prometheus text format, served at `/metrics`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+static HEARTBEAT_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+
+pub fn record_heartbeat_timeout() {
+    HEARTBEAT_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn heartbeat_timeouts() -> u64 {
+    HEARTBEAT_TIMEOUTS.load(Ordering::Relaxed)
+}
+
+pub struct ServerMetrics {
+    pub sessions: usize,
+    pub heartbeat_timeouts: u64,
+    /// Time until the chat server answered
+    pub lag: Duration,
+    pub rooms: Vec<RoomMetrics>,
+}
+
+pub struct RoomMetrics {
+    pub name: String,
+    pub members: usize,
+    pub messages: u64,
+    pub rolls: u64,
+    /// Time until the room answered
+    pub lag: Duration,
+}
+
+pub fn render(metrics: &ServerMetrics) -> String {
+    let mut output = String::new();
+
+    header(
+        &mut output,
+        "eddrc_sessions",
+        "gauge",
+        "Connected websocket sessions",
+    );
+    output.push_str(&format!("eddrc_sessions {}\n", metrics.sessions));
+
+    header(&mut output, "eddrc_rooms", "gauge", "Number of rooms");
+    output.push_str(&format!("eddrc_rooms {}\n", metrics.rooms.len()));
+
+    header(
+        &mut output,
+        "eddrc_room_members",
+        "gauge",
+        "Members per room",
+    );
+    for room in metrics.rooms.iter() {
+        output.push_str(&format!(
+            "eddrc_room_members{{room=\"{}\"}} {}\n",
+            escape(&room.name),
+            room.members
+        ));
+    }
+
+    header(
+        &mut output,
+        "eddrc_room_messages_total",
+        "counter",
+        "Messages sent to each room",
+    );
+    for room in metrics.rooms.iter() {
+        output.push_str(&format!(
+            "eddrc_room_messages_total{{room=\"{}\"}} {}\n",
+            escape(&room.name),
+            room.messages
+        ));
+    }
+
+    header(
+        &mut output,
+        "eddrc_room_rolls_total",
+        "counter",
+        "Dice rolls in each room",
+    );
+    for room in metrics.rooms.iter() {
+        output.push_str(&format!(
+            "eddrc_room_rolls_total{{room=\"{}\"}} {}\n",
+            escape(&room.name),
+            room.rolls
+        ));
+    }
+
+    header(
+        &mut output,
+        "eddrc_heartbeat_timeouts_total",
+        "counter",
+        "Websocket clients disconnected for missing heartbeats",
+    );
+    output.push_str(&format!(
+        "eddrc_heartbeat_timeouts_total {}\n",
+        metrics.heartbeat_timeouts
+    ));
+
+    header(
+        &mut output,
+        "eddrc_mailbox_lag_seconds",
+        "gauge",
+        "Time until an actor answered the metrics request",
+    );
+    output.push_str(&format!(
+        "eddrc_mailbox_lag_seconds{{actor=\"server\"}} {}\n",
+        metrics.lag.as_secs_f64()
+    ));
+    for room in metrics.rooms.iter() {
+        output.push_str(&format!(
+            "eddrc_mailbox_lag_seconds{{actor=\"room\",room=\"{}\"}} {}\n",
+            escape(&room.name),
+            room.lag.as_secs_f64()
+        ));
+    }
+
+    output
+}
+
+fn header(output: &mut String, name: &str, kind: &str, help: &str) {
+    output.push_str(&format!(
+        "# HELP {} {}\n# TYPE {} {}\n",
+        name, help, name, kind
+    ));
+}
+
+/// Escapes a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let output = render(&ServerMetrics {
+            sessions: 3,
+            heartbeat_timeouts: 1,
+            lag: Duration::from_millis(2),
+            rooms: vec![RoomMetrics {
+                name: "The \"Kaer\"".to_string(),
+                members: 2,
+                messages: 10,
+                rolls: 4,
+                lag: Duration::from_millis(1),
+            }],
+        });
+
+        assert!(output.contains("\neddrc_sessions 3\n"));
+        assert!(output.contains("\neddrc_rooms 1\n"));
+        assert!(output.contains("\neddrc_room_rolls_total{room=\"The \\\"Kaer\\\"\"} 4\n"));
+        assert!(output.contains("\neddrc_mailbox_lag_seconds{actor=\"server\"} 0.002\n"));
+        assert!(output.contains("# TYPE eddrc_heartbeat_timeouts_total counter\n"));
+    }
+}
@@ -0,0 +1,263 @@
+//! Moderation of a room: game masters, their aliases, bans and mutes. Bans
+//! and mutes apply to the name and the remote address of a member, so that
+//! they survive a reconnect or a new name. Names are not authenticated, so
+//! a name alone is only advisory: anyone can pick another one, and only the
+//! addresses keep a banned member out.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// A ban or mute, `until` is `None` for ever
+struct Sanction {
+    name: String,
+    /// Addresses of all the members which had the name
+    remote_addrs: Vec<String>,
+    until: Option<Instant>,
+}
+
+impl Sanction {
+    fn applies(&self, name: &str, remote_addr: Option<&str>, now: Instant) -> bool {
+        let same_addr =
+            matches!(remote_addr, Some(addr) if self.remote_addrs.iter().any(|a| a == addr));
+        is_active(self.until, now) && (self.name == name || same_addr)
+    }
+}
+
+fn is_active(until: Option<Instant>, now: Instant) -> bool {
+    match until {
+        Some(until) => now < until,
+        None => true,
+    }
+}
+
+#[derive(Default)]
+pub struct Moderation {
+    /// Session ids of the game masters
+    gms: HashSet<usize>,
+    /// Names a game master may speak and roll as, by the name of the member
+    aliases: HashMap<String, BTreeSet<String>>,
+    bans: Vec<Sanction>,
+    mutes: Vec<Sanction>,
+}
+
+impl Moderation {
+    pub fn is_gm(&self, id: usize) -> bool {
+        self.gms.contains(&id)
+    }
+
+    /// Makes a member game master or takes it back, returns whether the member
+    /// is game master now
+    pub fn toggle_gm(&mut self, id: usize) -> bool {
+        if self.gms.remove(&id) {
+            false
+        } else {
+            self.gms.insert(id);
+            true
+        }
+    }
+
+    pub fn remove_gm(&mut self, id: usize) {
+        self.gms.remove(&id);
+    }
+
+    pub fn add_alias(&mut self, name: &str, alias: &str) -> bool {
+        self.aliases
+            .entry(name.to_owned())
+            .or_default()
+            .insert(alias.to_owned())
+    }
+
+    pub fn remove_alias(&mut self, name: &str, alias: &str) -> bool {
+        match self.aliases.get_mut(name) {
+            Some(aliases) => aliases.remove(alias),
+            None => false,
+        }
+    }
+
+    pub fn aliases(&self, name: &str) -> Vec<String> {
+        match self.aliases.get(name) {
+            Some(aliases) => aliases.iter().cloned().collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn has_alias(&self, name: &str, alias: &str) -> bool {
+        matches!(self.aliases.get(name), Some(aliases) if aliases.contains(alias))
+    }
+
+    pub fn ban(
+        &mut self,
+        name: &str,
+        remote_addrs: &[String],
+        duration: Option<Duration>,
+        now: Instant,
+    ) -> Result<(), String> {
+        add(&mut self.bans, name, remote_addrs, duration, now)
+    }
+
+    pub fn unban(&mut self, name: &str) -> bool {
+        remove(&mut self.bans, name)
+    }
+
+    pub fn is_banned(&self, name: &str, remote_addr: Option<&str>, now: Instant) -> bool {
+        self.bans.iter().any(|b| b.applies(name, remote_addr, now))
+    }
+
+    pub fn mute(
+        &mut self,
+        name: &str,
+        remote_addrs: &[String],
+        duration: Option<Duration>,
+        now: Instant,
+    ) -> Result<(), String> {
+        add(&mut self.mutes, name, remote_addrs, duration, now)
+    }
+
+    pub fn unmute(&mut self, name: &str) -> bool {
+        remove(&mut self.mutes, name)
+    }
+
+    pub fn is_muted(&self, name: &str, remote_addr: Option<&str>, now: Instant) -> bool {
+        self.mutes.iter().any(|m| m.applies(name, remote_addr, now))
+    }
+}
+
+/// Replaces the sanction of the name, the addresses of an earlier one stay
+fn add(
+    sanctions: &mut Vec<Sanction>,
+    name: &str,
+    remote_addrs: &[String],
+    duration: Option<Duration>,
+    now: Instant,
+) -> Result<(), String> {
+    let until = match duration {
+        Some(duration) => Some(
+            now.checked_add(duration)
+                .ok_or_else(|| "the duration is too long".to_string())?,
+        ),
+        None => None,
+    };
+    let mut addrs = remote_addrs.to_vec();
+    for earlier in sanctions
+        .iter()
+        .filter(|s| s.name == name && is_active(s.until, now))
+    {
+        for addr in earlier.remote_addrs.iter() {
+            if !addrs.contains(addr) {
+                addrs.push(addr.clone());
+            }
+        }
+    }
+    // expired sanctions are dropped on the way
+    sanctions.retain(|s| s.name != name && is_active(s.until, now));
+    sanctions.push(Sanction {
+        name: name.to_owned(),
+        remote_addrs: addrs,
+        until,
+    });
+    Ok(())
+}
+
+fn remove(sanctions: &mut Vec<Sanction>, name: &str) -> bool {
+    let count = sanctions.len();
+    sanctions.retain(|s| s.name != name);
+    sanctions.len() != count
+}
+
+/// Parses durations like `30s`, `10m`, `2h` or `1d`, plain numbers are minutes
+pub fn parse_duration(input: &str) -> Option<Duration> {
+    let digits = input.chars().take_while(|c| c.is_ascii_digit()).count();
+    let value: u64 = input[..digits].parse().ok()?;
+    let seconds = match &input[digits..] {
+        "s" => 1,
+        "" | "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    value.checked_mul(seconds).map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ban_and_mute() {
+        let now = Instant::now();
+        let mut moderation = Moderation::default();
+        let addrs = |addrs: &[&str]| addrs.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        moderation
+            .ban("Troll", &addrs(&["10.0.0.9"]), None, now)
+            .unwrap();
+        assert!(moderation.is_banned("Troll", None, now));
+        assert!(moderation.is_banned("Not a Troll", Some("10.0.0.9"), now));
+        assert!(!moderation.is_banned("Thorin", Some("10.0.0.1"), now));
+        assert!(moderation.unban("Troll"));
+        assert!(!moderation.is_banned("Troll", Some("10.0.0.9"), now));
+
+        moderation
+            .mute("Thorin", &[], Some(Duration::from_secs(60)), now)
+            .unwrap();
+        assert!(moderation.is_muted("Thorin", Some("10.0.0.1"), now));
+        assert!(!moderation.is_muted("Thorin", None, now + Duration::from_secs(61)));
+        assert!(!moderation.is_muted("Elrond", None, now));
+
+        // all the addresses of the name stay banned
+        moderation
+            .ban("Troll", &addrs(&["10.0.0.9", "10.0.0.10"]), None, now)
+            .unwrap();
+        moderation
+            .ban("Troll", &addrs(&["10.0.0.11"]), None, now)
+            .unwrap();
+        for addr in ["10.0.0.9", "10.0.0.10", "10.0.0.11"].iter() {
+            assert!(moderation.is_banned("Not a Troll", Some(addr), now));
+        }
+    }
+
+    #[test]
+    fn test_long_duration() {
+        let now = Instant::now();
+        let mut moderation = Moderation::default();
+        let duration = parse_duration("200000000000000d");
+        assert!(duration.is_some());
+        assert!(moderation.ban("Troll", &[], duration, now).is_err());
+        assert!(moderation.mute("Troll", &[], duration, now).is_err());
+        assert!(!moderation.is_banned("Troll", None, now));
+    }
+
+    #[test]
+    fn test_gm() {
+        let mut moderation = Moderation::default();
+        assert!(moderation.toggle_gm(3));
+        assert!(moderation.is_gm(3));
+        assert!(!moderation.toggle_gm(3));
+        assert!(!moderation.is_gm(3));
+    }
+
+    #[test]
+    fn test_aliases() {
+        let mut moderation = Moderation::default();
+        assert!(moderation.add_alias("GM", "Vizier Kratt"));
+        assert!(!moderation.add_alias("GM", "Vizier Kratt"));
+        moderation.add_alias("GM", "Ork Scorcher");
+        assert!(moderation.has_alias("GM", "Vizier Kratt"));
+        assert!(!moderation.has_alias("Thorin", "Vizier Kratt"));
+        assert_eq!(
+            vec!["Ork Scorcher", "Vizier Kratt"],
+            moderation.aliases("GM")
+        );
+        assert!(moderation.remove_alias("GM", "Vizier Kratt"));
+        assert!(!moderation.has_alias("GM", "Vizier Kratt"));
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(Some(Duration::from_secs(30)), parse_duration("30s"));
+        assert_eq!(Some(Duration::from_secs(600)), parse_duration("10"));
+        assert_eq!(Some(Duration::from_secs(7200)), parse_duration("2h"));
+        assert_eq!(Some(Duration::from_secs(86400)), parse_duration("1d"));
+        assert_eq!(None, parse_duration("forever"));
+        assert_eq!(None, parse_duration("5y"));
+    }
+}
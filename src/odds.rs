@@ -0,0 +1,496 @@
+//! Exact probability distributions of dice expressions, i.e. to answer "what
+//! are my odds of hitting 12 with step 10?". Exploding dice are followed until
+//! the remaining probability becomes negligible.
+
+use std::collections::BTreeMap;
+
+use crate::dice::{expand_steps, parse, DiceExpression, DiceLimits, DiceTerm, Keep, TermKind};
+use crate::messages::{OddsDTO, PercentileDTO, SuccessLevelDTO};
+
+type Distribution = BTreeMap<i32, f64>;
+
+/// Explosion chains less likely than this are not followed any further
+const NEGLIGIBLE_PROBABILITY: f64 = 1e-12;
+/// Upper limit for the additions of a single calculation
+const MAX_WORK: usize = 2_000_000;
+/// Upper limit for the number of possible results of an expression
+const MAX_VALUES: usize = 10_000;
+const PERCENTILES: [u32; 5] = [10, 25, 50, 75, 90];
+/// In Earthdawn every 5 points above the target number are an extra success
+const SUCCESS_LEVEL_STEP: i32 = 5;
+
+/// Calculates the odds of a roll like `[10]`, `!!2d6+3` or `!d20kh1`. Rolls
+/// without a leading `!` are exploding, like the step rolls of the client.
+/// Rolls within the dice limits can still be too expensive to calculate.
+pub fn calculate(roll: &str, target: Option<i32>, limits: &DiceLimits) -> Result<OddsDTO, String> {
+    let roll = roll.trim();
+    let request = if roll.starts_with('!') {
+        expand_steps(roll, 0)
+    } else {
+        expand_steps(&format!("!!{}", roll), 0)
+    };
+
+    let expression = parse(&request[1..], limits)?;
+    if expression.terms.is_empty() {
+        return Err(format!("invalid roll '{}'", roll));
+    }
+
+    let mut budget = MAX_WORK;
+    Ok(summarize(
+        &request,
+        &distribution(&expression, &mut budget)?,
+        target,
+    ))
+}
+
+/// The expected result of a roll, calculated per die without the whole
+/// distribution so that it is cheap enough for every roll. `None` for kept
+/// exploding dice.
+pub fn mean(expression: &DiceExpression) -> Option<f64> {
+    let mut mean = 0.0;
+    for term in expression.terms.iter() {
+        let term_mean = match &term.kind {
+            TermKind::Dice(dice) => dice_mean(dice)?,
+            TermKind::Number(i) => f64::from(*i),
+        };
+        mean += if term.negative { -term_mean } else { term_mean };
+    }
+    Some(mean)
+}
+
+fn dice_mean(dice: &DiceTerm) -> Option<f64> {
+    if dice.sides < 1 {
+        return Some(0.0);
+    }
+
+    let faces = face_probabilities(dice);
+    let first: f64 = faces.iter().zip(1..).map(|(p, f)| p * f64::from(f)).sum();
+    let die_mean = match dice.explode_at {
+        Some(threshold) => {
+            let index = threshold.saturating_sub(1) as usize;
+            let explodes: f64 = faces.iter().skip(index).sum();
+            let again = f64::from(dice.sides.saturating_sub(index as u32)) / f64::from(dice.sides);
+            // expected number of plain rolls after the first explosion
+            let explosions = f64::from(dice.max_explosions);
+            let chain = if again < 1.0 {
+                (1.0 - again.powf(explosions)) / (1.0 - again)
+            } else {
+                explosions
+            };
+            first + explodes * f64::from(dice.sides + 1) / 2.0 * chain
+        }
+        None => first,
+    };
+
+    let count = f64::from(dice.count);
+    match dice.keep {
+        None => Some(count * die_mean),
+        Some(_) if dice.explode_at.is_some() => None,
+        Some(Keep::Highest(keep)) => Some(highest_mean(&faces, dice.count, keep)),
+        Some(Keep::Lowest(keep)) => Some(
+            count * die_mean - highest_mean(&faces, dice.count, dice.count.saturating_sub(keep)),
+        ),
+    }
+}
+
+/// Expected sum of the highest `keep` of `count` dice, which is the sum over
+/// all faces of the expected number of kept dice showing at least that face
+fn highest_mean(faces: &[f64], count: u32, keep: u32) -> f64 {
+    let count = count as usize;
+    let keep = (keep as usize).min(count);
+    let mut at_least = 0.0;
+    let mut mean = 0.0;
+    for probability in faces.iter().rev() {
+        at_least = (at_least + probability).min(1.0);
+        // E[min(N, keep)] of the binomial number N of dice with at least the face
+        let mut below_keep = 0.0;
+        let mut expected = 0.0;
+        for n in 0..keep {
+            let p = binomial(count, n)
+                * at_least.powi(n as i32)
+                * (1.0 - at_least).powi((count - n) as i32);
+            below_keep += p;
+            expected += p * n as f64;
+        }
+        mean += expected + (1.0 - below_keep) * keep as f64;
+    }
+    mean
+}
+
+/// Takes `work` from the budget of the calculation, fails if it is used up
+fn spend(budget: &mut usize, work: usize) -> Result<(), String> {
+    *budget = budget
+        .checked_sub(work)
+        .ok_or_else(|| "the odds of this roll are too expensive to calculate".to_string())?;
+    Ok(())
+}
+
+fn check_size(distribution: &Distribution) -> Result<(), String> {
+    if distribution.len() > MAX_VALUES {
+        return Err(format!(
+            "odds can only be calculated for rolls with up to {} possible results",
+            MAX_VALUES
+        ));
+    }
+    Ok(())
+}
+
+fn distribution(expression: &DiceExpression, budget: &mut usize) -> Result<Distribution, String> {
+    let mut distribution = point(0);
+    for term in expression.terms.iter() {
+        let term_distribution = match &term.kind {
+            TermKind::Dice(dice) => dice_distribution(dice, budget)?,
+            TermKind::Number(i) => point(*i),
+        };
+        let term_distribution = if term.negative {
+            term_distribution
+                .into_iter()
+                .map(|(v, p)| (-v, p))
+                .collect()
+        } else {
+            term_distribution
+        };
+        spend(budget, distribution.len() * term_distribution.len())?;
+        distribution = convolve(&distribution, &term_distribution);
+        check_size(&distribution)?;
+    }
+
+    Ok(distribution)
+}
+
+fn summarize(request: &str, distribution: &Distribution, target: Option<i32>) -> OddsDTO {
+    let total: f64 = distribution.values().sum();
+    let mean = distribution.iter().map(|(v, p)| *v as f64 * p).sum::<f64>() / total;
+
+    let percentiles = PERCENTILES
+        .iter()
+        .map(|&percentile| {
+            let mut cumulative = 0.0;
+            let value = distribution
+                .iter()
+                .find(|(_, p)| {
+                    cumulative += *p / total;
+                    cumulative >= percentile as f64 / 100.0
+                })
+                .map(|(v, _)| *v)
+                .unwrap_or_else(|| *distribution.keys().last().unwrap());
+            PercentileDTO { percentile, value }
+        })
+        .collect();
+
+    let success_levels = match target {
+        Some(target) => success_levels(distribution, total, target),
+        None => vec![],
+    };
+
+    OddsDTO {
+        roll: request.to_owned(),
+        target,
+        mean,
+        percentiles,
+        success_levels,
+        distribution: distribution.iter().map(|(v, p)| (*v, p / total)).collect(),
+    }
+}
+
+/// Probability of each number of successes, 0 being a failure
+fn success_levels(distribution: &Distribution, total: f64, target: i32) -> Vec<SuccessLevelDTO> {
+    let mut levels: BTreeMap<u32, f64> = BTreeMap::new();
+    for (value, probability) in distribution.iter() {
+        let successes = if *value < target {
+            0
+        } else {
+            ((value - target) / SUCCESS_LEVEL_STEP) as u32 + 1
+        };
+        *levels.entry(successes).or_insert(0.0) += probability / total;
+    }
+
+    levels
+        .into_iter()
+        .map(|(successes, probability)| SuccessLevelDTO {
+            successes,
+            probability,
+        })
+        .collect()
+}
+
+fn dice_distribution(dice: &DiceTerm, budget: &mut usize) -> Result<Distribution, String> {
+    if dice.sides < 1 {
+        return Ok(point(0));
+    }
+
+    let die = die_distribution(dice, budget)?;
+    match dice.keep {
+        Some(Keep::Highest(keep)) => kept_distribution(&die, dice.count, keep, true, budget),
+        Some(Keep::Lowest(keep)) => kept_distribution(&die, dice.count, keep, false, budget),
+        None => {
+            let mut sum = point(0);
+            for _ in 0..dice.count {
+                spend(budget, sum.len() * die.len())?;
+                sum = convolve(&sum, &die);
+                check_size(&sum)?;
+            }
+            Ok(sum)
+        }
+    }
+}
+
+/// Probability of each face of the first roll of a die, after a reroll
+fn face_probabilities(dice: &DiceTerm) -> Vec<f64> {
+    let face = 1.0 / dice.sides as f64;
+    let rerolled = match dice.reroll_at_most {
+        Some(r) => r.min(dice.sides) as f64 * face,
+        None => 0.0,
+    };
+
+    (1..=dice.sides)
+        .map(|f| {
+            let kept = match dice.reroll_at_most {
+                Some(r) if f <= r => 0.0,
+                _ => face,
+            };
+            kept + rerolled * face
+        })
+        .collect()
+}
+
+/// Distribution of a single die, including a reroll and explosions
+fn die_distribution(dice: &DiceTerm, budget: &mut usize) -> Result<Distribution, String> {
+    let sides = dice.sides as i32;
+    let face = 1.0 / dice.sides as f64;
+    let first_roll: Distribution = (1..=sides).zip(face_probabilities(dice)).collect();
+
+    let threshold = match dice.explode_at {
+        Some(threshold) => threshold as i32,
+        None => return Ok(first_roll),
+    };
+
+    let mut result = Distribution::new();
+    let mut exploding = Distribution::new();
+    for (f, p) in first_roll {
+        let target = if f < threshold {
+            &mut result
+        } else {
+            &mut exploding
+        };
+        *target.entry(f).or_insert(0.0) += p;
+    }
+
+    let mut depth = 0;
+    while exploding.values().sum::<f64>() > NEGLIGIBLE_PROBABILITY && depth < dice.max_explosions {
+        spend(budget, exploding.len() * dice.sides as usize)?;
+        let mut next = Distribution::new();
+        for (sum, p) in exploding {
+            for f in 1..=sides {
+                let target = if f < threshold {
+                    &mut result
+                } else {
+                    &mut next
+                };
+                *target.entry(sum + f).or_insert(0.0) += p * face;
+            }
+        }
+        exploding = next;
+        depth += 1;
+    }
+
+    check_size(&result)?;
+    Ok(result)
+}
+
+/// Distribution of the sum of the highest (or lowest) `keep` of `count` dice.
+/// The die values are assigned from the best to the worst value, the first
+/// `keep` assigned dice are kept.
+fn kept_distribution(
+    die: &Distribution,
+    count: u32,
+    keep: u32,
+    highest: bool,
+    budget: &mut usize,
+) -> Result<Distribution, String> {
+    let count = count as usize;
+    let keep = keep as usize;
+    let values: Vec<(i32, f64)> = if highest {
+        die.iter().rev().map(|(v, p)| (*v, *p)).collect()
+    } else {
+        die.iter().map(|(v, p)| (*v, *p)).collect()
+    };
+
+    // assigned dice -> distribution of the kept sum
+    let mut states: Vec<Distribution> = vec![Distribution::new(); count + 1];
+    states[0].insert(0, 1.0);
+    for (value, probability) in values {
+        let mut next: Vec<Distribution> = vec![Distribution::new(); count + 1];
+        let sums: usize = states.iter().map(Distribution::len).sum();
+        spend(budget, sums * (count + 1))?;
+        for (assigned, sums) in states.iter().enumerate() {
+            let remaining = count - assigned;
+            for with_value in 0..=remaining {
+                let weight = binomial(remaining, with_value) * probability.powi(with_value as i32);
+                if weight < NEGLIGIBLE_PROBABILITY {
+                    continue;
+                }
+                let kept = (assigned + with_value).min(keep) - assigned.min(keep);
+                for (sum, p) in sums.iter() {
+                    *next[assigned + with_value]
+                        .entry(sum + kept as i32 * value)
+                        .or_insert(0.0) += p * weight;
+                }
+            }
+        }
+        states = next;
+    }
+
+    let distribution = states.pop().unwrap();
+    check_size(&distribution)?;
+    Ok(distribution)
+}
+
+fn binomial(n: usize, k: usize) -> f64 {
+    (0..k).fold(1.0, |result, i| result * (n - i) as f64 / (i + 1) as f64)
+}
+
+fn convolve(a: &Distribution, b: &Distribution) -> Distribution {
+    let mut result = Distribution::new();
+    for (va, pa) in a.iter() {
+        for (vb, pb) in b.iter() {
+            *result.entry(va + vb).or_insert(0.0) += pa * pb;
+        }
+    }
+    result
+}
+
+fn point(value: i32) -> Distribution {
+    let mut distribution = Distribution::new();
+    distribution.insert(value, 1.0);
+    distribution
+}
+
+/// Human readable summary for the chat
+pub fn describe(odds: &OddsDTO) -> String {
+    let percentiles: Vec<String> = odds
+        .percentiles
+        .iter()
+        .map(|p| format!("{}%: {}", p.percentile, p.value))
+        .collect();
+    let mut description = format!(
+        "Odds for {}: mean {:.1}, percentiles {}",
+        odds.roll,
+        odds.mean,
+        percentiles.join(", ")
+    );
+
+    if let Some(target) = odds.target {
+        let success: f64 = odds
+            .success_levels
+            .iter()
+            .filter(|l| l.successes > 0)
+            .map(|l| l.probability)
+            .sum();
+        description.push_str(&format!(
+            "<br>Target number {}: success {:.1}%",
+            target,
+            success * 100.0
+        ));
+        for level in odds.success_levels.iter() {
+            if level.probability >= 0.0005 {
+                description.push_str(&format!(
+                    "<br>{} success(es): {:.1}%",
+                    level.successes,
+                    level.probability * 100.0
+                ));
+            }
+        }
+    }
+
+    description
+}
+
+#[cfg(test)]
+mod test {
+    use super::mean;
+    use crate::dice::{parse, DiceLimits};
+    use crate::messages::OddsDTO;
+
+    fn calculate(roll: &str, target: Option<i32>) -> Result<OddsDTO, String> {
+        super::calculate(roll, target, &DiceLimits::default())
+    }
+
+    fn probability(odds: &crate::messages::OddsDTO, successes: u32) -> f64 {
+        odds.success_levels
+            .iter()
+            .find(|l| l.successes == successes)
+            .map(|l| l.probability)
+            .unwrap_or(0.0)
+    }
+
+    #[test]
+    fn test_plain_dice() {
+        let odds = calculate("!2d6", Some(7)).unwrap();
+        assert!((odds.mean - 7.0).abs() < 1e-9);
+        assert!((probability(&odds, 0) - 15.0 / 36.0).abs() < 1e-9);
+        assert_eq!(7, odds.percentiles[2].value);
+    }
+
+    #[test]
+    fn test_exploding_dice() {
+        // an exploding d6 has a mean of 3.5 * 6 / 5
+        let odds = calculate("[4]", None).unwrap();
+        assert!((odds.mean - 4.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_keep_and_reroll() {
+        let odds = calculate("!2d20kh1", Some(20)).unwrap();
+        assert!((probability(&odds, 1) - 39.0 / 400.0).abs() < 1e-9);
+
+        let odds = calculate("!4d6dl1", None).unwrap();
+        assert!((odds.mean - 15869.0 / 1296.0).abs() < 1e-9);
+
+        let odds = calculate("!d6r1", None).unwrap();
+        assert!((odds.mean - (20.0 / 6.0 + 3.5 / 6.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_invalid_roll() {
+        assert!(calculate("abc", None).is_err());
+        assert!(calculate("!101d6", None).is_err());
+
+        let limits = DiceLimits {
+            max_sides: 10,
+            ..DiceLimits::default()
+        };
+        assert!(super::calculate("!d12", None, &limits).is_err());
+    }
+
+    #[test]
+    fn test_mean() {
+        for roll in [
+            "2d6+3", "d6!", "4d6dl1", "2d20kh1", "3d8kl2r1", "d10!>9-2", "d6r2!",
+        ]
+        .iter()
+        {
+            let expression = parse(roll, &DiceLimits::default()).unwrap();
+            let expected = calculate(&format!("!{}", roll), None).unwrap().mean;
+            assert!(
+                (mean(&expression).unwrap() - expected).abs() < 1e-6,
+                "{}",
+                roll
+            );
+        }
+        let expression = parse("2d6!kh1", &DiceLimits::default()).unwrap();
+        assert!(mean(&expression).is_none());
+
+        // too expensive for the exact distribution, but not for the mean
+        let expression = parse("40d1000", &DiceLimits::default()).unwrap();
+        assert!(mean(&expression).unwrap() > 0.0);
+    }
+
+    #[test]
+    fn test_expensive_roll() {
+        assert!(calculate("!d200!>2", None).is_err());
+        assert!(calculate("!5d1000!>2", None).is_err());
+        assert!(calculate("!40d1000", None).is_err());
+        assert!(calculate("!d20!>11", None).is_ok());
+    }
+}
@@ -0,0 +1,354 @@
+//! Token bucket rate limits for the websocket sessions, per session and per
+//! remote address.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::Deserialize;
+
+use crate::arguments::split_first_argument;
+
+/// Number of remote addresses after which idle buckets are dropped
+const PRUNE_THRESHOLD: usize = 1000;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Limit {
+    /// Actions per second once the burst is used up
+    pub rate: f64,
+    /// Actions allowed at once
+    pub burst: u32,
+}
+
+impl Limit {
+    pub const fn new(rate: f64, burst: u32) -> Self {
+        Limit { rate, burst }
+    }
+}
+
+/// Limits of the different kinds of websocket messages
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ActionLimits {
+    /// Chat messages and commands
+    pub chat: Limit,
+    pub rolls: Limit,
+    pub joins: Limit,
+    /// `/typing` notifications, the indicator lasts for seconds
+    pub typing: Limit,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    pub session: ActionLimits,
+    /// Shared by all sessions of a remote address
+    pub ip: ActionLimits,
+    /// New websocket connections per remote address
+    pub connections: Limit,
+    /// Violations after which a session is disconnected
+    pub strikes: Limit,
+}
+
+impl RateLimits {
+    pub fn validate(&self) -> Result<(), String> {
+        let limits = [
+            self.session.chat,
+            self.session.rolls,
+            self.session.joins,
+            self.session.typing,
+            self.ip.chat,
+            self.ip.rolls,
+            self.ip.joins,
+            self.ip.typing,
+            self.connections,
+            self.strikes,
+        ];
+        if limits
+            .iter()
+            .any(|l| l.rate.is_nan() || l.rate <= 0.0 || l.burst == 0)
+        {
+            return Err("rate limits need a positive rate and burst".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for ActionLimits {
+    fn default() -> Self {
+        ActionLimits {
+            chat: Limit::new(2.0, 10),
+            rolls: Limit::new(2.0, 10),
+            joins: Limit::new(0.2, 3),
+            typing: Limit::new(0.5, 1),
+        }
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            session: ActionLimits::default(),
+            ip: ActionLimits {
+                chat: Limit::new(10.0, 50),
+                rolls: Limit::new(10.0, 50),
+                joins: Limit::new(1.0, 10),
+                typing: Limit::new(2.5, 10),
+            },
+            connections: Limit::new(1.0, 20),
+            strikes: Limit::new(0.1, 10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Chat,
+    Roll,
+    Join,
+    /// `/typing`, it doesn't count as chat
+    Typing,
+}
+
+impl Action {
+    /// The action of a websocket message, `/as` and `/in` count as the message
+    /// they wrap
+    pub fn of(text: &str) -> Action {
+        let text = text.trim_start();
+        if let Some(wrapped) = text
+            .strip_prefix("/as ")
+            .or_else(|| text.strip_prefix("/in "))
+        {
+            if let Some((_, inner)) = split_first_argument(wrapped) {
+                return Action::of(inner);
+            }
+        }
+        if text.starts_with("/join") {
+            Action::Join
+        } else if text.starts_with("/typing") {
+            Action::Typing
+        } else if text.starts_with('!') || text.starts_with("/repeat") {
+            Action::Roll
+        } else {
+            Action::Chat
+        }
+    }
+}
+
+pub struct TokenBucket {
+    limit: Limit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: Limit, now: Instant) -> Self {
+        TokenBucket {
+            limit,
+            tokens: f64::from(limit.burst),
+            updated: now,
+        }
+    }
+
+    /// Takes a token if there is one left
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(f64::from(self.limit.burst));
+        self.updated = now;
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= f64::from(self.limit.burst)
+    }
+}
+
+/// One bucket for each kind of message
+pub struct ActionBuckets {
+    chat: TokenBucket,
+    rolls: TokenBucket,
+    joins: TokenBucket,
+    typing: TokenBucket,
+}
+
+impl ActionBuckets {
+    pub fn new(limits: &ActionLimits, now: Instant) -> Self {
+        ActionBuckets {
+            chat: TokenBucket::new(limits.chat, now),
+            rolls: TokenBucket::new(limits.rolls, now),
+            joins: TokenBucket::new(limits.joins, now),
+            typing: TokenBucket::new(limits.typing, now),
+        }
+    }
+
+    pub fn try_take(&mut self, action: Action, now: Instant) -> bool {
+        self.bucket(action).try_take(now)
+    }
+
+    fn bucket(&mut self, action: Action) -> &mut TokenBucket {
+        match action {
+            Action::Chat => &mut self.chat,
+            Action::Roll => &mut self.rolls,
+            Action::Join => &mut self.joins,
+            Action::Typing => &mut self.typing,
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.chat.is_full(now)
+            && self.rolls.is_full(now)
+            && self.joins.is_full(now)
+            && self.typing.is_full(now)
+    }
+}
+
+struct AddressBuckets {
+    actions: ActionBuckets,
+    connections: TokenBucket,
+}
+
+/// Rate limits per remote address, shared by all sessions
+pub struct AddressLimiter {
+    limits: RateLimits,
+    buckets: Mutex<HashMap<String, AddressBuckets>>,
+}
+
+impl AddressLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        AddressLimiter {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    /// Whether a new websocket connection from the address is allowed
+    pub fn try_connect(&self, address: &str, now: Instant) -> bool {
+        self.with_buckets(address, now, |buckets| buckets.connections.try_take(now))
+    }
+
+    pub fn try_take(&self, address: &str, action: Action, now: Instant) -> bool {
+        self.with_buckets(address, now, |buckets| {
+            buckets.actions.try_take(action, now)
+        })
+    }
+
+    fn with_buckets<F>(&self, address: &str, now: Instant, f: F) -> bool
+    where
+        F: FnOnce(&mut AddressBuckets) -> bool,
+    {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD && !buckets.contains_key(address) {
+            // addresses with full buckets are in the same state as new ones
+            buckets.retain(|_, b| !(b.actions.is_full(now) && b.connections.is_full(now)));
+        }
+        let limits = &self.limits;
+        let entry = buckets
+            .entry(address.to_string())
+            .or_insert_with(|| AddressBuckets {
+                actions: ActionBuckets::new(&limits.ip, now),
+                connections: TokenBucket::new(limits.connections, now),
+            });
+        f(entry)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(Limit::new(2.0, 3), start);
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start));
+
+        // refills two tokens per second, up to the burst
+        assert!(bucket.try_take(start + Duration::from_millis(500)));
+        assert!(!bucket.try_take(start + Duration::from_millis(500)));
+        assert!(bucket.is_full(start + Duration::from_secs(10)));
+        assert!(bucket.try_take(start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_address_limiter() {
+        let start = Instant::now();
+        let limiter = AddressLimiter::new(RateLimits {
+            connections: Limit::new(1.0, 2),
+            ..RateLimits::default()
+        });
+        assert!(limiter.try_connect("10.0.0.1", start));
+        assert!(limiter.try_connect("10.0.0.1", start));
+        assert!(!limiter.try_connect("10.0.0.1", start));
+        assert!(limiter.try_connect("10.0.0.2", start));
+
+        // the joins are limited independent of the connections
+        assert!(limiter.try_take("10.0.0.1", Action::Join, start));
+    }
+
+    #[test]
+    fn test_typing() {
+        let start = Instant::now();
+        let mut buckets = ActionBuckets::new(
+            &ActionLimits {
+                chat: Limit::new(1.0, 1),
+                typing: Limit::new(0.5, 1),
+                ..ActionLimits::default()
+            },
+            start,
+        );
+        assert!(buckets.try_take(Action::Typing, start));
+        assert!(!buckets.try_take(Action::Typing, start));
+        assert!(buckets.try_take(Action::Typing, start + Duration::from_secs(2)));
+        // typing leaves the chat messages alone
+        assert!(buckets.try_take(Action::Chat, start));
+
+        // but counts for the address
+        let limiter = AddressLimiter::new(RateLimits {
+            ip: ActionLimits {
+                typing: Limit::new(0.5, 2),
+                ..ActionLimits::default()
+            },
+            ..RateLimits::default()
+        });
+        assert!(limiter.try_take("10.0.0.1", Action::Typing, start));
+        assert!(limiter.try_take("10.0.0.1", Action::Typing, start));
+        assert!(!limiter.try_take("10.0.0.1", Action::Typing, start));
+        assert!(limiter.try_take("10.0.0.1", Action::Chat, start));
+    }
+
+    #[test]
+    fn test_action() {
+        assert_eq!(Action::Chat, Action::of("Hello"));
+        assert_eq!(Action::Roll, Action::of(" !!2d6"));
+        assert_eq!(Action::Roll, Action::of("/repeat 3 !!2d6"));
+        assert_eq!(Action::Join, Action::of("/join Main"));
+        assert_eq!(Action::Typing, Action::of("/typing"));
+        assert_eq!(Action::Roll, Action::of("/as \"Vizier Kratt\" !!2d6"));
+        assert_eq!(Action::Chat, Action::of("/as \"Vizier Kratt\" Hello"));
+        assert_eq!(
+            Action::Roll,
+            Action::of("/in \"GM notes\" !![9] Perception")
+        );
+        assert_eq!(Action::Roll, Action::of("/in Notes /as Kratt !!2d6"));
+        assert_eq!(Action::Chat, Action::of("/in Notes"));
+    }
+}
@@ -1,10 +1,36 @@
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use crate::messages::{OutgoingMessageDTO, RoomStateMessageDTO, TextMessageDTO};
-use crate::{GetNameMsg, WsChatSession};
+use crate::access::{Credential, RoomAccess};
+use crate::board::Board;
+use crate::combat::{parse_initiative, Combat, Turn};
+use crate::dice::{
+    annotate_roll, expand_steps, has_step, hide_roll_part, is_hidden_roll, parse,
+    split_repetitions, totals, DiceLimits, MAX_REPETITIONS,
+};
+use crate::effects::{EffectCommand, Effects};
+use crate::history::{History, SessionFilter};
+use crate::messages::{
+    BookmarkKind, CombatStateDTO, ErrorCode, ErrorDTO, HistoryDTO, InitiativeDTO, KickDTO,
+    MemberDTO, MemberLeftDTO, MemberRenamedDTO, MessageDeletedDTO, MessageKind, OutgoingMessageDTO,
+    Presence, RoomStateMessageDTO, StatsDTO, TermResultDTO, TextMessageDTO,
+};
+use crate::metrics::RoomMetrics;
+use crate::moderation::Moderation;
+use crate::odds;
+use crate::stats::RoomStats;
+use crate::storage::{PersistedRoom, Storage};
+use crate::WsChatSession;
 use actix::prelude::*;
+use chrono::Utc;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+/// How often changed room state is written to the storage
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a member is shown as typing after the last typing notification
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Send message to specific room
 #[derive(Message)]
@@ -16,17 +42,53 @@ pub struct ClientMessage {
     pub msg: TextMessageDTO,
 }
 
-/// ChatRoom sends this messages to session
+/// Dice roll of a member, rolled by the room to apply the effects on the sender
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct RoomMessage(pub OutgoingMessageDTO);
+pub struct RollMessage {
+    /// Id of the client session
+    pub id: usize,
+    pub sender: String,
+    /// Name a game master rolls as, i.e. for an NPC
+    pub alias: Option<String>,
+    /// Roll request, i.e. `!![8]+2 Spear`
+    pub text: String,
+}
 
+/// ChatRoom sends this messages to session
 #[derive(Message)]
 #[rtype(result = "()")]
+pub struct RoomMessage {
+    /// The room which sent the message, `None` for messages of the server
+    pub room: Option<String>,
+    pub message: OutgoingMessageDTO,
+}
+
+impl RoomMessage {
+    pub fn from_room(room: &str, message: OutgoingMessageDTO) -> Self {
+        RoomMessage {
+            room: Some(room.to_owned()),
+            message,
+        }
+    }
+
+    pub fn from_server(message: OutgoingMessageDTO) -> Self {
+        RoomMessage {
+            room: None,
+            message,
+        }
+    }
+}
+
+/// Joins the room, fails if the room is locked and the credential is wrong
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
 pub struct JoinRoomMessage {
     pub id: usize,
     pub name: String,
     pub session_addr: Addr<WsChatSession>,
+    pub remote_addr: Option<String>,
+    pub credential: Option<Credential>,
 }
 
 #[derive(Message)]
@@ -36,124 +98,1432 @@ pub struct LeaveRoomMessage {
     pub id: usize,
 }
 
+/// The session of a member disconnected, the member stays in the room for the
+/// resume grace period
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SuspendMember {
+    pub id: usize,
+    pub name: String,
+}
+
+/// A suspended member reconnected, it gets the messages it missed
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ResumeMember {
+    pub id: usize,
+    pub name: String,
+    pub session_addr: Addr<WsChatSession>,
+    pub remote_addr: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct NameChangedMessage {
+    pub id: usize,
+    pub name: String,
+}
+
+/// The session became idle or away, or is back online
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PresenceMessage {
+    pub id: usize,
+    pub presence: Presence,
+}
+
+/// The member is writing a message, shown to the others until it is sent or
+/// for a few seconds
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct TypingMessage {
+    pub id: usize,
+}
+
+/// Runs the combat, only the owner and game masters may do this
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CombatMessage {
+    /// Id of the requesting session
+    pub id: usize,
+    pub command: CombatCommand,
+}
+
+pub enum CombatCommand {
+    Start,
+    NextTurn,
+    NextRound,
+    End,
+}
+
+/// Changes the effects on a character, members may only change their own
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct EffectMessage {
+    /// Id of the requesting session
+    pub id: usize,
+    pub command: EffectCommand,
+}
+
+/// Locks or unlocks the room, only the owner may do this
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct AccessMessage {
+    /// Id of the requesting session
+    pub id: usize,
+    pub command: AccessCommand,
+}
+
+pub enum AccessCommand {
+    Status,
+    /// Locks with a password, or for invites only
+    Lock(Option<String>),
+    Unlock,
+    Invite,
+}
+
+/// Lists, adds or removes the aliases of a game master
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct AliasMessage {
+    /// Id of the requesting session
+    pub id: usize,
+    pub command: AliasCommand,
+}
+
+pub enum AliasCommand {
+    List,
+    Add(String),
+    Remove(String),
+}
+
+/// Shows or changes the topic, description and pins of the room
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct BoardMessage {
+    /// Id of the requesting session
+    pub id: usize,
+    pub command: BoardCommand,
+}
+
+pub enum BoardCommand {
+    Show,
+    /// Sets the topic, `None` clears it
+    Topic(Option<String>),
+    Description(Option<String>),
+    Pin(u64),
+    Unpin(u64),
+}
+
+/// Changes the text of a message, only the author may do this
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct EditMessage {
+    /// Id of the requesting session
+    pub id: usize,
+    pub message_id: u64,
+    pub text: String,
+}
+
+/// Deletes a message of the author, the owner and game masters may delete any
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DeleteMessage {
+    /// Id of the requesting session
+    pub id: usize,
+    pub message_id: u64,
+}
+
+/// Kicks, bans or mutes a member, only the owner and game masters may do this
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct NameChangedMessage;
+pub struct ModerationMessage {
+    /// Id of the requesting session
+    pub id: usize,
+    pub command: ModerationCommand,
+}
+
+/// The commands take the name of the member, durations are for ever if `None`
+pub enum ModerationCommand {
+    Kick(String),
+    Ban(String, Option<Duration>),
+    Unban(String),
+    Mute(String, Option<Duration>),
+    Unmute(String),
+    /// Makes a member game master or takes it back, only for the owner
+    Gm(String),
+}
+
+/// Whether an invite token is valid for the room
+pub struct CheckInvite(pub String);
+
+impl actix::Message for CheckInvite {
+    type Result = bool;
+}
+
+/// Marks the start of a session or scene, only the owner and game masters
+/// may do this
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct BookmarkMessage {
+    /// Id of the requesting session
+    pub id: usize,
+    pub kind: BookmarkKind,
+    pub title: String,
+}
+
+/// Request the chat log of the room, as the viewer may see it
+pub struct LogMessage {
+    /// Id of the requesting session, `None` for anonymous requests
+    pub viewer: Option<usize>,
+    /// Proof of access to a locked room, needed if the viewer is no member
+    pub credential: Option<Credential>,
+    /// Only messages starting at this time (milliseconds since the epoch)
+    pub since: Option<i64>,
+    pub session: SessionFilter,
+}
+
+pub struct RoomLog {
+    pub room_name: String,
+    pub messages: Vec<TextMessageDTO>,
+}
+
+impl actix::Message for LogMessage {
+    type Result = Result<RoomLog, String>;
+}
+
+/// Writes the room state to the storage, i.e. before shutting down
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SaveRoom;
+
+/// Removes all members and stops the room, sent by the chat server
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CloseRoom;
+
+/// Request the counters of the room for `/metrics`
+pub struct GetRoomMetrics;
+
+impl actix::Message for GetRoomMetrics {
+    type Result = RoomMetrics;
+}
+
+/// Request the roll statistics of the room
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct StatsMessage {
+    /// Id of the requesting session
+    pub id: usize,
+}
+
+/// Settings shared by all rooms
+#[derive(Clone)]
+pub struct RoomSettings {
+    pub storage: Option<Arc<Storage>>,
+    /// How many messages a room keeps
+    pub history_size: usize,
+    /// How long disconnected sessions can be resumed
+    pub resume_grace_period: Duration,
+    pub dice_limits: DiceLimits,
+}
+
+struct Member {
+    addr: Addr<WsChatSession>,
+    name: String,
+    remote_addr: Option<String>,
+    /// As told by the session, typing is tracked by the room
+    presence: Presence,
+    /// Clears the typing indicator, `None` if the member is not typing
+    typing: Option<SpawnHandle>,
+}
+
+impl Member {
+    fn new(addr: Addr<WsChatSession>, name: String, remote_addr: Option<String>) -> Self {
+        Member {
+            addr,
+            name,
+            remote_addr,
+            presence: Presence::Online,
+            typing: None,
+        }
+    }
+
+    fn presence(&self) -> Presence {
+        self.presence.shown(self.typing.is_some())
+    }
+}
+
+/// A member whose session disconnected
+struct SuspendedMember {
+    name: String,
+    remote_addr: Option<String>,
+    /// Milliseconds since the epoch
+    since: i64,
+    expiry: SpawnHandle,
+}
 
 pub struct ChatRoom {
     name: String,
-    members: HashMap<usize, Addr<WsChatSession>>,
+    members: HashMap<usize, Member>,
+    suspended: HashMap<usize, SuspendedMember>,
+    resume_grace_period: Duration,
+    dice_limits: DiceLimits,
+    access: RoomAccess,
+    moderation: Moderation,
+    board: Board,
+    combat: Option<Combat>,
+    effects: Effects,
+    stats: RoomStats,
+    history: History,
+    storage: Option<Arc<Storage>>,
+    /// Whether the state changed since it was last saved
+    dirty: bool,
+    /// Messages sent to the room since it was started
+    message_count: u64,
+    /// Rolls since the room was started
+    roll_count: u64,
 }
 
 impl ChatRoom {
-    pub fn new(name: String) -> Self {
+    pub fn new(name: String, settings: RoomSettings) -> Self {
         ChatRoom {
             name,
             members: HashMap::new(),
+            suspended: HashMap::new(),
+            resume_grace_period: settings.resume_grace_period,
+            dice_limits: settings.dice_limits,
+            access: RoomAccess::new(),
+            moderation: Moderation::default(),
+            board: Board::default(),
+            combat: None,
+            effects: Effects::new(),
+            stats: RoomStats::default(),
+            history: History::new(settings.history_size),
+            storage: settings.storage,
+            dirty: false,
+            message_count: 0,
+            roll_count: 0,
         }
     }
 
-    fn send_to_all(&self, message: &TextMessageDTO) {
-        self.members.values().for_each(|session| {
-            let _ = session.do_send(RoomMessage(OutgoingMessageDTO::TextMessage(
-                message.clone(),
-            )));
-        });
+    fn save(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        if let Some(storage) = self.storage.as_ref() {
+            let state = PersistedRoom {
+                stats: self.stats.clone(),
+                history: self.history.clone(),
+                board: self.board.clone(),
+            };
+            match storage.save(&self.name, &state) {
+                Ok(()) => self.dirty = false,
+                Err(e) => error!("Could not save the state of room '{}': {}", self.name, e),
+            }
+        }
+    }
+
+    fn send_to_all(&mut self, message: &TextMessageDTO) {
+        self.send_from(None, message);
+    }
+
+    /// Sends a message of the session `author` to all members
+    fn send_from(&mut self, author: Option<usize>, message: &TextMessageDTO) {
+        let message = self.history.push(message.clone(), None, author);
+        self.broadcast(OutgoingMessageDTO::TextMessage(message));
+        self.dirty = true;
+        self.message_count += 1;
+    }
+
+    fn send_error(&self, id: usize, code: ErrorCode, message: &str) {
+        if let Some(member) = self.members.get(&id) {
+            member.addr.do_send(RoomMessage::from_room(
+                &self.name,
+                OutgoingMessageDTO::Error(ErrorDTO {
+                    code,
+                    message: message.to_string(),
+                }),
+            ));
+        }
     }
 
-    fn send_room_state(&self, ctx: &mut Context<Self>) {
-        let needed_answers = self.members.values().len();
-        let answers = Arc::new(Mutex::new(vec![]));
+    fn broadcast(&self, message: OutgoingMessageDTO) {
         self.members.values().for_each(|member| {
-            let answers_local = answers.clone();
-            let needed_answers_local = needed_answers;
             member
-                .send(GetNameMsg)
-                .into_actor(self)
-                .then(move |res, act, _| {
-                    let name = match res {
-                        Ok(name) => name,
-                        // something went wrong, likely a deadlock between the actors
-                        _ => "<<unknown name>>".to_string(),
-                    };
-                    ChatRoom::maybe_send_member_list(
-                        act,
-                        needed_answers_local,
-                        answers_local,
-                        name,
-                    );
-                    fut::ready(())
-                })
-                .wait(ctx)
+                .addr
+                .do_send(RoomMessage::from_room(&self.name, message.clone()));
         });
     }
 
-    fn maybe_send_member_list(
-        actor: &mut ChatRoom,
-        needed_answers: usize,
-        answers: Arc<Mutex<Vec<String>>>,
-        added_name: String,
-    ) {
-        let mut list = answers.lock().unwrap();
-        list.push(added_name);
-
-        if list.len() == needed_answers {
-            actor.members.values().for_each(|session| {
-                let _ = session.do_send(RoomMessage(OutgoingMessageDTO::RoomState(
-                    RoomStateMessageDTO {
-                        room_name: actor.name.clone(),
-                        members: list.clone(),
-                    },
-                )));
+    fn combat_state(&self) -> OutgoingMessageDTO {
+        OutgoingMessageDTO::CombatState(match self.combat.as_ref() {
+            Some(combat) => combat.to_dto(),
+            None => CombatStateDTO {
+                in_combat: false,
+                round: 0,
+                active: None,
+                order: vec![],
+            },
+        })
+    }
+
+    /// Collects initiative rolls into the initiative order while in combat
+    fn track_initiative(&mut self, message: &TextMessageDTO) {
+        let combat = match self.combat.as_mut() {
+            Some(combat) => combat,
+            None => return,
+        };
+        let (name, results) = match (message.display_name(), message.dice_results()) {
+            (Some(name), Some(results)) => (name, results),
+            _ => return,
+        };
+
+        if let Some((sub_name, description)) = parse_initiative(message.message()) {
+            combat.add_initiative(InitiativeDTO {
+                name: name.to_owned(),
+                sub_name,
+                result: results.iter().sum(),
+                description,
             });
+            self.broadcast(self.combat_state());
+        }
+    }
+
+    fn join(
+        &mut self,
+        id: usize,
+        name: &str,
+        session_addr: Addr<WsChatSession>,
+        remote_addr: Option<String>,
+        credential: Option<&Credential>,
+    ) -> Result<(), String> {
+        if self
+            .moderation
+            .is_banned(name, remote_addr.as_deref(), Instant::now())
+        {
+            return Err("you are banned from this room".to_string());
+        }
+        self.access.check(id, credential)?;
+        if self.access.claim(id, name) {
+            session_addr.do_send(RoomMessage::from_room(
+                &self.name,
+                OutgoingMessageDTO::TextMessage(TextMessageDTO::system(
+                    "You are the owner of this room (i.e. /room lock secret, /room invite)",
+                )),
+            ));
+        }
+
+        self.send_to_all(&TextMessageDTO::system(&format!(
+            "'{}' joined the room",
+            name
+        )));
+        self.broadcast(OutgoingMessageDTO::MemberJoined(MemberDTO {
+            id,
+            name: name.to_owned(),
+            presence: Presence::Online,
+        }));
+        session_addr.do_send(RoomMessage::from_room(&self.name, self.combat_state()));
+        self.members
+            .insert(id, Member::new(session_addr, name.to_owned(), remote_addr));
+        self.send_room_state(id);
+        Ok(())
+    }
+
+    fn is_moderator(&self, id: usize) -> bool {
+        self.access.is_owner(id) || self.moderation.is_gm(id)
+    }
+
+    /// Whether the member may speak or roll as the alias
+    fn check_alias(&self, id: usize, alias: &str) -> Result<(), String> {
+        let member = match self.members.get(&id) {
+            Some(member) => member,
+            None => return Err("you are not in the room".to_string()),
+        };
+        if !self.is_moderator(id) {
+            return Err("only the owner of the room and game masters can use aliases".to_string());
+        }
+        if !self.moderation.has_alias(&member.name, alias) {
+            return Err(format!(
+                "'{}' is not one of your aliases (i.e. /alias add \"{}\")",
+                alias, alias
+            ));
+        }
+        Ok(())
+    }
+
+    fn is_muted(&self, id: usize) -> bool {
+        match self.members.get(&id) {
+            Some(member) => self.moderation.is_muted(
+                &member.name,
+                member.remote_addr.as_deref(),
+                Instant::now(),
+            ),
+            None => false,
+        }
+    }
+
+    /// Whether a member, possibly suspended, has the name
+    fn is_member_name(&self, name: &str) -> bool {
+        self.members.values().any(|member| member.name == name)
+            || self.suspended.values().any(|member| member.name == name)
+    }
+
+    /// Addresses of the members with the name
+    fn remote_addrs(&self, name: &str) -> Vec<String> {
+        self.members
+            .values()
+            .filter(|member| member.name == name)
+            .filter_map(|member| member.remote_addr.clone())
+            .collect()
+    }
+
+    /// Removes the members with the name and tells them why
+    fn remove_members(&mut self, name: &str, reason: &str) -> Vec<Member> {
+        let ids: Vec<usize> = self
+            .members
+            .iter()
+            .filter(|(_, member)| member.name == name)
+            .map(|(id, _)| *id)
+            .collect();
+        let mut removed = vec![];
+        for id in ids {
+            if let Some(member) = self.members.remove(&id) {
+                member.addr.do_send(RoomMessage::from_room(
+                    &self.name,
+                    OutgoingMessageDTO::Kicked(KickDTO {
+                        room: Some(self.name.clone()),
+                        reason: reason.to_string(),
+                    }),
+                ));
+                self.access.release(id);
+                self.moderation.remove_gm(id);
+                self.broadcast(OutgoingMessageDTO::MemberLeft(MemberLeftDTO {
+                    id,
+                    name: member.name.clone(),
+                }));
+                removed.push(member);
+            }
+        }
+        removed
+    }
+
+    /// Sends a system message to a single member
+    fn reply(&self, id: usize, message: &str) {
+        if let Some(member) = self.members.get(&id) {
+            member.addr.do_send(RoomMessage::from_room(
+                &self.name,
+                OutgoingMessageDTO::TextMessage(TextMessageDTO::system(message)),
+            ));
         }
     }
+
+    fn broadcast_presence(&self, id: usize) {
+        if let Some(member) = self.members.get(&id) {
+            self.broadcast(OutgoingMessageDTO::Presence(MemberDTO {
+                id,
+                name: member.name.clone(),
+                presence: member.presence(),
+            }));
+        }
+    }
+
+    /// Clears the typing indicator of a member, i.e. after the message was sent
+    fn stop_typing(&mut self, id: usize, ctx: &mut Context<Self>) {
+        let handle = match self.members.get_mut(&id) {
+            Some(member) => member.typing.take(),
+            None => None,
+        };
+        if let Some(handle) = handle {
+            ctx.cancel_future(handle);
+            self.broadcast_presence(id);
+        }
+    }
+
+    fn room_state(&self) -> OutgoingMessageDTO {
+        let mut members: Vec<MemberDTO> = self
+            .members
+            .iter()
+            .map(|(id, member)| MemberDTO {
+                id: *id,
+                name: member.name.clone(),
+                presence: member.presence(),
+            })
+            .collect();
+        // suspended members are still listed, they are likely to come back
+        members.extend(self.suspended.iter().map(|(id, member)| MemberDTO {
+            id: *id,
+            name: member.name.clone(),
+            presence: Presence::Idle,
+        }));
+        members.sort_by_key(|member| member.id);
+        OutgoingMessageDTO::RoomState(RoomStateMessageDTO {
+            room_name: self.name.clone(),
+            members,
+            effects: self.effects.to_dto(),
+            topic: self.board.topic.clone(),
+            description: self.board.description.clone(),
+            pins: self.board.pins().to_vec(),
+        })
+    }
+
+    /// Sends the full room state to a single member, i.e. after joining
+    fn send_room_state(&self, id: usize) {
+        if let Some(member) = self.members.get(&id) {
+            member
+                .addr
+                .do_send(RoomMessage::from_room(&self.name, self.room_state()));
+        }
+    }
+
+    /// Sends the full room state to all members after the effects or the
+    /// board changed, member changes are sent as deltas
+    fn broadcast_room_state(&self) {
+        self.broadcast(self.room_state());
+    }
 }
 
 impl Actor for ChatRoom {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(storage) = self.storage.as_ref() {
+            if let Some(state) = storage.load(&self.name) {
+                self.stats = state.stats;
+                self.board = state.board;
+                let limit = self.history.limit();
+                self.history = state.history;
+                self.history.set_limit(limit);
+            }
+            ctx.run_interval(SAVE_INTERVAL, |act, _| act.save());
+        }
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        self.save();
+        Running::Stop
+    }
 }
 
 /// Handler for Message message.
 impl Handler<ClientMessage> for ChatRoom {
     type Result = ();
 
-    fn handle(&mut self, msg: ClientMessage, _: &mut Context<Self>) {
+    fn handle(&mut self, msg: ClientMessage, ctx: &mut Context<Self>) {
         debug!("got message {:?}", msg.msg);
-        self.send_to_all(&msg.msg);
+        self.stop_typing(msg.id, ctx);
+        if self.is_muted(msg.id) {
+            return self.send_error(msg.id, ErrorCode::Muted, "you are muted in this room");
+        }
+        if let Some(alias) = msg.msg.alias() {
+            if let Err(error) = self.check_alias(msg.id, alias) {
+                return self.reply(msg.id, &format!("!!! {}", error));
+            }
+        }
+        self.send_from(Some(msg.id), &msg.msg);
+    }
+}
+
+impl Handler<RollMessage> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: RollMessage, ctx: &mut Context<Self>) {
+        self.stop_typing(msg.id, ctx);
+        if self.is_muted(msg.id) {
+            return self.send_error(msg.id, ErrorCode::Muted, "you are muted in this room");
+        }
+        if let Some(alias) = msg.alias.as_ref() {
+            if let Err(error) = self.check_alias(msg.id, alias) {
+                return self.reply(msg.id, &format!("!!! {}", error));
+            }
+        }
+        let (repetitions, text) = split_repetitions(&msg.text);
+        if !(1..=MAX_REPETITIONS).contains(&repetitions) {
+            if let Some(member) = self.members.get(&msg.id) {
+                member.addr.do_send(RoomMessage::from_room(
+                    &self.name,
+                    OutgoingMessageDTO::TextMessage(TextMessageDTO::system(&format!(
+                        "!!! a roll can be repeated 1 to {} times",
+                        MAX_REPETITIONS
+                    ))),
+                ));
+            }
+            return;
+        }
+
+        // the effects of an NPC are on its alias
+        let effects = self.effects.on(msg.alias.as_ref().unwrap_or(&msg.sender));
+        let request = if has_step(&text) && !effects.is_empty() {
+            let modifier = effects.iter().map(|e| e.modifier).sum();
+            let applied: Vec<String> = effects
+                .iter()
+                .map(|e| format!("{} {:+}", e.name, e.modifier))
+                .collect();
+            annotate_roll(&expand_steps(&text, modifier), &applied.join(", "))
+        } else {
+            expand_steps(&text, 0)
+        };
+
+        let expression = match parse(&request[1..], &self.dice_limits) {
+            Ok(expression) => expression,
+            Err(error) => return self.send_error(msg.id, ErrorCode::InvalidRoll, &error),
+        };
+        let max_dice = self.dice_limits.max_dice;
+        if expression.dice_count() * u64::from(repetitions) > u64::from(max_dice) {
+            let error = format!("a message can roll at most {} dice", max_dice);
+            return self.send_error(msg.id, ErrorCode::TooManyDice, &error);
+        }
+        let rolls: Vec<Vec<TermResultDTO>> = (0..repetitions).map(|_| expression.roll()).collect();
+        // the statistics would give away the dice of hidden rolls
+        if !is_hidden_roll(&request) {
+            let expected = odds::mean(&expression);
+            // rolls of an alias count for the alias, not for the game master
+            let name = msg.alias.as_deref().unwrap_or(&msg.sender);
+            for roll in rolls.iter() {
+                self.stats.record(name, roll, expected);
+            }
+        }
+        self.dirty = true;
+        self.roll_count += u64::from(repetitions);
+
+        if is_hidden_roll(&request) {
+            let message =
+                hidden_roll_message(&request, &rolls, &msg.sender).with_alias(msg.alias.clone());
+            debug!("rolled {:?}", message);
+            let details = roll_message(&request, rolls, &msg.sender).with_alias(msg.alias);
+            let message = self.history.push(message, Some(details), Some(msg.id));
+            self.broadcast(OutgoingMessageDTO::TextMessage(message.clone()));
+            self.message_count += 1;
+            self.track_initiative(&message);
+        } else {
+            let message = roll_message(&request, rolls, &msg.sender).with_alias(msg.alias);
+            debug!("rolled {:?}", message);
+            self.send_from(Some(msg.id), &message);
+            self.track_initiative(&message);
+        }
     }
 }
 
 impl Handler<JoinRoomMessage> for ChatRoom {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: JoinRoomMessage, _: &mut Context<Self>) -> Self::Result {
+        self.join(
+            msg.id,
+            &msg.name,
+            msg.session_addr,
+            msg.remote_addr,
+            msg.credential.as_ref(),
+        )
+    }
+}
+
+impl Handler<SuspendMember> for ChatRoom {
     type Result = ();
 
-    fn handle(&mut self, msg: JoinRoomMessage, ctx: &mut Context<Self>) {
-        self.send_to_all(&TextMessageDTO::system(&format!(
-            "'{}' joined the room",
-            msg.name
-        )));
-        self.members.insert(msg.id, msg.session_addr);
-        self.send_room_state(ctx);
+    fn handle(&mut self, msg: SuspendMember, ctx: &mut Context<Self>) {
+        let remote_addr = match self.members.remove(&msg.id) {
+            Some(member) => member.remote_addr,
+            None => return,
+        };
+
+        let id = msg.id;
+        let expiry = ctx.run_later(self.resume_grace_period, move |act, _| {
+            if let Some(member) = act.suspended.remove(&id) {
+                act.access.release(id);
+                act.moderation.remove_gm(id);
+                act.send_to_all(&TextMessageDTO::system(&format!(
+                    "'{}' left the room",
+                    member.name
+                )));
+                act.broadcast(OutgoingMessageDTO::MemberLeft(MemberLeftDTO {
+                    id,
+                    name: member.name,
+                }));
+            }
+        });
+        self.broadcast(OutgoingMessageDTO::Presence(MemberDTO {
+            id,
+            name: msg.name.clone(),
+            presence: Presence::Idle,
+        }));
+        self.suspended.insert(
+            msg.id,
+            SuspendedMember {
+                name: msg.name,
+                remote_addr,
+                since: Utc::now().timestamp_millis(),
+                expiry,
+            },
+        );
+    }
+}
+
+impl Handler<ResumeMember> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: ResumeMember, ctx: &mut Context<Self>) {
+        let member = match self.suspended.remove(&msg.id) {
+            Some(member) => member,
+            // the grace period of the room is over, join again
+            None => {
+                let session_addr = msg.session_addr.clone();
+                let joined = self.join(msg.id, &msg.name, msg.session_addr, msg.remote_addr, None);
+                if let Err(reason) = joined {
+                    session_addr.do_send(RoomMessage::from_room(
+                        &self.name,
+                        OutgoingMessageDTO::Kicked(KickDTO {
+                            room: Some(self.name.clone()),
+                            reason,
+                        }),
+                    ));
+                }
+                return;
+            }
+        };
+        ctx.cancel_future(member.expiry);
+
+        let missed = self
+            .history
+            .visible_to(Some(msg.id), Some(member.since), &SessionFilter::All);
+        if !missed.is_empty() {
+            msg.session_addr.do_send(RoomMessage::from_room(
+                &self.name,
+                OutgoingMessageDTO::History(HistoryDTO {
+                    room_name: self.name.clone(),
+                    session: None,
+                    messages: missed,
+                }),
+            ));
+        }
+        msg.session_addr
+            .do_send(RoomMessage::from_room(&self.name, self.combat_state()));
+        self.members.insert(
+            msg.id,
+            Member::new(msg.session_addr, msg.name, member.remote_addr),
+        );
+        self.broadcast_presence(msg.id);
+        self.send_room_state(msg.id);
     }
 }
 
 impl Handler<NameChangedMessage> for ChatRoom {
     type Result = ();
 
-    fn handle(&mut self, _: NameChangedMessage, ctx: &mut Context<Self>) {
-        self.send_room_state(ctx);
+    fn handle(&mut self, msg: NameChangedMessage, _: &mut Context<Self>) {
+        let old_name = match self.members.get_mut(&msg.id) {
+            Some(member) => std::mem::replace(&mut member.name, msg.name.clone()),
+            None => return,
+        };
+        self.broadcast(OutgoingMessageDTO::MemberRenamed(MemberRenamedDTO {
+            id: msg.id,
+            old_name,
+            new_name: msg.name,
+        }));
+    }
+}
+
+impl Handler<PresenceMessage> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: PresenceMessage, _: &mut Context<Self>) {
+        if let Some(member) = self.members.get_mut(&msg.id) {
+            if member.presence == msg.presence {
+                return;
+            }
+            member.presence = msg.presence;
+            // the others see the member typing until it is done
+            if member.typing.is_none() {
+                self.broadcast_presence(msg.id);
+            }
+        }
+    }
+}
+
+impl Handler<TypingMessage> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: TypingMessage, ctx: &mut Context<Self>) {
+        if self.is_muted(msg.id) {
+            return;
+        }
+        let member = match self.members.get_mut(&msg.id) {
+            Some(member) => member,
+            None => return,
+        };
+        let id = msg.id;
+        let clear = ctx.run_later(TYPING_TIMEOUT, move |act, _| {
+            if let Some(member) = act.members.get_mut(&id) {
+                member.typing = None;
+                act.broadcast_presence(id);
+            }
+        });
+        match member.typing.replace(clear) {
+            Some(previous) => {
+                ctx.cancel_future(previous);
+            }
+            None => self.broadcast_presence(id),
+        }
     }
 }
 
 impl Handler<LeaveRoomMessage> for ChatRoom {
     type Result = ();
 
-    fn handle(&mut self, msg: LeaveRoomMessage, ctx: &mut Context<Self>) {
+    fn handle(&mut self, msg: LeaveRoomMessage, _: &mut Context<Self>) {
         self.members.remove(&msg.id);
+        self.access.release(msg.id);
+        self.moderation.remove_gm(msg.id);
         self.send_to_all(&TextMessageDTO::system(&format!(
             "'{}' left the room",
             msg.name
         )));
-        self.send_room_state(ctx);
+        self.broadcast(OutgoingMessageDTO::MemberLeft(MemberLeftDTO {
+            id: msg.id,
+            name: msg.name,
+        }));
+    }
+}
+
+impl Handler<AccessMessage> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: AccessMessage, _: &mut Context<Self>) {
+        if let AccessCommand::Status = msg.command {
+            return self.reply(msg.id, &self.access.describe());
+        }
+        if !self.access.is_owner(msg.id) {
+            return self.reply(msg.id, "!!! only the owner of the room can do this");
+        }
+
+        match msg.command {
+            AccessCommand::Status => {}
+            AccessCommand::Lock(password) => {
+                let announcement = match password {
+                    Some(_) => "The room was locked with a password",
+                    None => "The room is now invite only",
+                };
+                self.access.lock(password);
+                self.send_to_all(&TextMessageDTO::system(announcement));
+            }
+            AccessCommand::Unlock => {
+                self.access.unlock();
+                self.send_to_all(&TextMessageDTO::system("The room was unlocked"));
+            }
+            AccessCommand::Invite => {
+                let token = self.access.create_invite();
+                let link = format!(
+                    "/static/application.html?room={}&invite={}",
+                    utf8_percent_encode(&self.name, NON_ALPHANUMERIC),
+                    token
+                );
+                self.reply(
+                    msg.id,
+                    &format!(
+                        "Invite link: {} (or /join {} invite:{})",
+                        link, self.name, token
+                    ),
+                );
+            }
+        }
+    }
+}
+
+impl Handler<CheckInvite> for ChatRoom {
+    type Result = bool;
+
+    fn handle(&mut self, msg: CheckInvite, _: &mut Context<Self>) -> Self::Result {
+        self.access.is_valid_invite(&msg.0)
+    }
+}
+
+impl Handler<CombatMessage> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: CombatMessage, _: &mut Context<Self>) {
+        if !self.is_moderator(msg.id) {
+            return self.reply(
+                msg.id,
+                "!!! only the owner of the room and game masters can manage a combat",
+            );
+        }
+        let announcement = match (msg.command, self.combat.as_mut()) {
+            (CombatCommand::Start, Some(_)) => "Combat is already running".to_string(),
+            (CombatCommand::Start, None) => {
+                self.combat = Some(Combat::new());
+                "Combat started, round 1. Roll your initiative (i.e. !![6] (ini))".to_string()
+            }
+            (_, None) => "There is no combat running (start one with /combat start)".to_string(),
+            (CombatCommand::NextTurn, Some(combat)) => {
+                let round = combat.round();
+                match combat.next_turn() {
+                    Turn::Actor(actor) => match actor.sub_name.as_ref() {
+                        Some(sub_name) => format!(
+                            "Round {}: it's the turn of '{}' ({})",
+                            round, actor.name, sub_name
+                        ),
+                        None => format!("Round {}: it's the turn of '{}'", round, actor.name),
+                    },
+                    Turn::EndOfRound => format!(
+                        "Everyone has acted in round {}. Start the next one with /combat round",
+                        round
+                    ),
+                    Turn::NoInitiatives => "Nobody has rolled initiative yet".to_string(),
+                }
+            }
+            (CombatCommand::NextRound, Some(combat)) => {
+                combat.next_round();
+                let mut announcement =
+                    format!("Round {} begins. Roll your initiative", combat.round());
+                for effect in self.effects.advance_round() {
+                    announcement.push_str(&format!(
+                        "<br>'{}' on {} expired",
+                        effect.name, effect.target
+                    ));
+                }
+                self.broadcast_room_state();
+                announcement
+            }
+            (CombatCommand::End, Some(_)) => {
+                self.combat = None;
+                "Combat ended".to_string()
+            }
+        };
+
+        self.send_to_all(&TextMessageDTO::system(&announcement));
+        self.broadcast(self.combat_state());
+    }
+}
+
+impl Handler<EffectMessage> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: EffectMessage, _: &mut Context<Self>) {
+        let target = msg.command.target();
+        let own = matches!(
+            self.members.get(&msg.id),
+            Some(member) if member.name.eq_ignore_ascii_case(target)
+        );
+        if !own && !self.is_moderator(msg.id) {
+            return self.reply(
+                msg.id,
+                "!!! only the owner of the room and game masters can change the effects of others",
+            );
+        }
+        let announcement = match msg.command {
+            EffectCommand::Add(effect) => {
+                let announcement = match effect.rounds {
+                    Some(rounds) => format!(
+                        "{} is affected by '{}' ({:+}) for {} round(s)",
+                        effect.target, effect.name, effect.modifier, rounds
+                    ),
+                    None => format!(
+                        "{} is affected by '{}' ({:+})",
+                        effect.target, effect.name, effect.modifier
+                    ),
+                };
+                self.effects.add(effect);
+                announcement
+            }
+            EffectCommand::Remove { target, name } => {
+                if self.effects.remove(&target, &name) {
+                    format!("'{}' on {} was removed", name, target)
+                } else {
+                    format!("{} is not affected by '{}'", target, name)
+                }
+            }
+            EffectCommand::Clear { target } => {
+                self.effects.clear(&target);
+                format!("All effects on {} were removed", target)
+            }
+        };
+
+        self.send_to_all(&TextMessageDTO::system(&announcement));
+        self.broadcast_room_state();
+    }
+}
+
+/// Creates the message for the rolls of a request like `!!2d6+3 Spear`.
+/// Multiple rolls of a repeated request are combined into a single message.
+fn roll_message(request: &str, rolls: Vec<Vec<TermResultDTO>>, sender: &str) -> TextMessageDTO {
+    let results: Vec<Vec<i32>> = rolls.iter().map(|roll| totals(roll)).collect();
+    let message = if results.len() == 1 {
+        TextMessageDTO::dice_result(request, &results[0], sender)
+    } else {
+        TextMessageDTO::repeated_dice_result(
+            &format!("!{}x{}", results.len(), &request[1..]),
+            &results,
+            sender,
+        )
+    };
+    message.with_breakdown(rolls)
+}
+
+/// Creates the message for a request like `!!2d6+3* Spear`, which only shows
+/// the total of each roll
+fn hidden_roll_message(
+    request: &str,
+    rolls: &[Vec<TermResultDTO>],
+    sender: &str,
+) -> TextMessageDTO {
+    let message = hide_roll_part(request);
+    let results: Vec<Vec<i32>> = rolls
+        .iter()
+        .map(|roll| vec![totals(roll).iter().sum()])
+        .collect();
+    if results.len() == 1 {
+        TextMessageDTO::dice_result(&message, &results[0], sender)
+    } else {
+        TextMessageDTO::repeated_dice_result(
+            &format!("!{}x{}", results.len(), &message[1..]),
+            &results,
+            sender,
+        )
+    }
+}
+
+impl Handler<BoardMessage> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: BoardMessage, _: &mut Context<Self>) {
+        if let BoardCommand::Show = msg.command {
+            let topic = self.board.topic.as_deref().unwrap_or("no topic");
+            let description = match self.board.description.as_ref() {
+                Some(description) => format!("<br>{}", description),
+                None => String::new(),
+            };
+            return self.reply(msg.id, &format!("Topic: {}{}", topic, description));
+        }
+        if !self.is_moderator(msg.id) {
+            return self.reply(
+                msg.id,
+                "!!! only the owner of the room and game masters can do this",
+            );
+        }
+
+        let announcement = match msg.command {
+            BoardCommand::Show => return,
+            BoardCommand::Topic(topic) => {
+                let announcement = match topic.as_ref() {
+                    Some(topic) => format!("The topic is now: {}", topic),
+                    None => "The topic was cleared".to_string(),
+                };
+                self.board.topic = topic;
+                announcement
+            }
+            BoardCommand::Description(description) => {
+                let announcement = match description {
+                    Some(_) => "The description of the room was changed",
+                    None => "The description of the room was cleared",
+                };
+                self.board.description = description;
+                announcement.to_string()
+            }
+            BoardCommand::Pin(id) => {
+                let message = match self.history.get(id) {
+                    Some(message) => message.clone(),
+                    None => return self.reply(msg.id, "!!! unknown message"),
+                };
+                if let Err(error) = self.board.pin(message) {
+                    return self.reply(msg.id, &format!("!!! {}", error));
+                }
+                format!("Message #{} was pinned", id)
+            }
+            BoardCommand::Unpin(id) => {
+                if !self.board.unpin(id) {
+                    return self.reply(msg.id, "!!! the message is not pinned");
+                }
+                format!("Message #{} was unpinned", id)
+            }
+        };
+        self.dirty = true;
+        self.send_to_all(&TextMessageDTO::system(&announcement));
+        self.broadcast_room_state();
+    }
+}
+
+impl Handler<AliasMessage> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: AliasMessage, _: &mut Context<Self>) {
+        if !self.is_moderator(msg.id) {
+            return self.reply(
+                msg.id,
+                "!!! only the owner of the room and game masters can use aliases",
+            );
+        }
+        let name = match self.members.get(&msg.id) {
+            Some(member) => member.name.clone(),
+            None => return,
+        };
+
+        let reply = match msg.command {
+            AliasCommand::List => match self.moderation.aliases(&name) {
+                aliases if aliases.is_empty() => {
+                    "You have no aliases (i.e. /alias add \"Vizier Kratt\")".to_string()
+                }
+                aliases => format!("Your aliases: {}", aliases.join(", ")),
+            },
+            AliasCommand::Add(alias) if self.is_member_name(&alias) => {
+                format!("!!! '{}' is the name of a member", alias)
+            }
+            AliasCommand::Add(alias) => {
+                self.moderation.add_alias(&name, &alias);
+                format!(
+                    "You can speak and roll as '{}' (i.e. /as \"{}\" Hello)",
+                    alias, alias
+                )
+            }
+            AliasCommand::Remove(alias) => {
+                if self.moderation.remove_alias(&name, &alias) {
+                    format!("'{}' was removed from your aliases", alias)
+                } else {
+                    format!("!!! '{}' is not one of your aliases", alias)
+                }
+            }
+        };
+        self.reply(msg.id, &reply);
+    }
+}
+
+impl Handler<EditMessage> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: EditMessage, _: &mut Context<Self>) {
+        if self.is_muted(msg.id) {
+            return self.send_error(msg.id, ErrorCode::Muted, "you are muted in this room");
+        }
+        let error = match self.history.get(msg.message_id) {
+            None => Some("unknown message"),
+            Some(message) if message.kind() == MessageKind::Roll => Some("rolls can't be edited"),
+            Some(_) if !self.history.is_author(msg.message_id, msg.id) => {
+                Some("only the author can edit a message")
+            }
+            Some(_) => None,
+        };
+        if let Some(error) = error {
+            return self.reply(msg.id, &format!("!!! {}", error));
+        }
+        if let Some(message) = self.history.edit(msg.message_id, &msg.text) {
+            self.dirty = true;
+            let pinned = self.board.update(&message);
+            self.broadcast(OutgoingMessageDTO::MessageEdited(message));
+            if pinned {
+                self.broadcast_room_state();
+            }
+        }
+    }
+}
+
+impl Handler<DeleteMessage> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeleteMessage, _: &mut Context<Self>) {
+        let allowed = match self.history.get(msg.message_id) {
+            None => return self.reply(msg.id, "!!! unknown message"),
+            Some(_) => self.history.is_author(msg.message_id, msg.id) || self.is_moderator(msg.id),
+        };
+        if !allowed {
+            return self.reply(msg.id, "!!! only the author can delete a message");
+        }
+        self.history.delete(msg.message_id);
+        self.dirty = true;
+        self.broadcast(OutgoingMessageDTO::MessageDeleted(MessageDeletedDTO {
+            id: msg.message_id,
+        }));
+        if self.board.unpin(msg.message_id) {
+            self.broadcast_room_state();
+        }
+    }
+}
+
+impl Handler<ModerationMessage> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: ModerationMessage, _: &mut Context<Self>) {
+        if !self.is_moderator(msg.id) {
+            return self.reply(
+                msg.id,
+                "!!! only the owner of the room and game masters can do this",
+            );
+        }
+        let now = Instant::now();
+        if let ModerationCommand::Kick(name)
+        | ModerationCommand::Ban(name, _)
+        | ModerationCommand::Mute(name, _) = &msg.command
+        {
+            let is_owner =
+                |(id, member): (&usize, &Member)| member.name == *name && self.access.is_owner(*id);
+            if self.members.iter().any(is_owner) {
+                return self.reply(
+                    msg.id,
+                    "!!! the owner of the room can't be kicked, banned or muted",
+                );
+            }
+        }
+
+        match msg.command {
+            ModerationCommand::Kick(name) => {
+                if self.remove_members(&name, "You were kicked").is_empty() {
+                    return self.reply(msg.id, &format!("!!! '{}' is not in the room", name));
+                }
+                self.send_to_all(&TextMessageDTO::system(&format!(
+                    "'{}' was kicked from the room",
+                    name
+                )));
+            }
+            ModerationCommand::Ban(name, duration) => {
+                let remote_addrs = self.remote_addrs(&name);
+                if let Err(error) = self.moderation.ban(&name, &remote_addrs, duration, now) {
+                    return self.reply(msg.id, &format!("!!! {}", error));
+                }
+                self.remove_members(&name, "You were banned");
+                self.send_to_all(&TextMessageDTO::system(&format!(
+                    "'{}' was banned from the room{}",
+                    name,
+                    describe_duration(duration)
+                )));
+            }
+            ModerationCommand::Unban(name) => {
+                if self.moderation.unban(&name) {
+                    self.reply(msg.id, &format!("'{}' is no longer banned", name));
+                } else {
+                    self.reply(msg.id, &format!("!!! '{}' is not banned", name));
+                }
+            }
+            ModerationCommand::Mute(name, duration) => {
+                let remote_addrs = self.remote_addrs(&name);
+                if let Err(error) = self.moderation.mute(&name, &remote_addrs, duration, now) {
+                    return self.reply(msg.id, &format!("!!! {}", error));
+                }
+                self.send_to_all(&TextMessageDTO::system(&format!(
+                    "'{}' was muted{}",
+                    name,
+                    describe_duration(duration)
+                )));
+            }
+            ModerationCommand::Unmute(name) => {
+                if self.moderation.unmute(&name) {
+                    self.send_to_all(&TextMessageDTO::system(&format!(
+                        "'{}' is no longer muted",
+                        name
+                    )));
+                } else {
+                    self.reply(msg.id, &format!("!!! '{}' is not muted", name));
+                }
+            }
+            ModerationCommand::Gm(name) => {
+                if !self.access.is_owner(msg.id) {
+                    return self.reply(msg.id, "!!! only the owner of the room can do this");
+                }
+                let id = self
+                    .members
+                    .iter()
+                    .find(|(_, member)| member.name == name)
+                    .map(|(id, _)| *id);
+                let id = match id {
+                    Some(id) => id,
+                    None => {
+                        return self.reply(msg.id, &format!("!!! '{}' is not in the room", name))
+                    }
+                };
+                let announcement = if self.moderation.toggle_gm(id) {
+                    format!("'{}' is now game master", name)
+                } else {
+                    format!("'{}' is no longer game master", name)
+                };
+                self.send_to_all(&TextMessageDTO::system(&announcement));
+            }
+        }
+    }
+}
+
+fn describe_duration(duration: Option<Duration>) -> String {
+    match duration {
+        Some(duration) => format!(" for {} minutes", duration.as_secs().div_ceil(60)),
+        None => String::new(),
+    }
+}
+
+impl Handler<StatsMessage> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: StatsMessage, _: &mut Context<Self>) {
+        if let Some(member) = self.members.get(&msg.id) {
+            member.addr.do_send(RoomMessage::from_room(
+                &self.name,
+                OutgoingMessageDTO::Stats(StatsDTO {
+                    room_name: self.name.clone(),
+                    players: self.stats.to_dto(),
+                }),
+            ));
+        }
+    }
+}
+
+impl Handler<LogMessage> for ChatRoom {
+    type Result = Result<RoomLog, String>;
+
+    fn handle(&mut self, msg: LogMessage, _: &mut Context<Self>) -> Self::Result {
+        if !matches!(msg.viewer, Some(id) if self.members.contains_key(&id)) {
+            self.access.check_credential(msg.credential.as_ref())?;
+        }
+        Ok(RoomLog {
+            room_name: self.name.clone(),
+            messages: self.history.visible_to(msg.viewer, msg.since, &msg.session),
+        })
+    }
+}
+
+impl Handler<BookmarkMessage> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: BookmarkMessage, _: &mut Context<Self>) {
+        if !self.is_moderator(msg.id) {
+            return self.reply(
+                msg.id,
+                "!!! only the owner of the room and game masters can start a session or scene",
+            );
+        }
+        self.send_to_all(&TextMessageDTO::bookmark_message(msg.kind, &msg.title));
+    }
+}
+
+impl Handler<GetRoomMetrics> for ChatRoom {
+    type Result = MessageResult<GetRoomMetrics>;
+
+    fn handle(&mut self, _: GetRoomMetrics, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(RoomMetrics {
+            name: self.name.clone(),
+            members: self.members.len(),
+            messages: self.message_count,
+            rolls: self.roll_count,
+            lag: Duration::default(),
+        })
+    }
+}
+
+impl Handler<CloseRoom> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, _: CloseRoom, ctx: &mut Context<Self>) {
+        self.broadcast(OutgoingMessageDTO::Kicked(KickDTO {
+            room: Some(self.name.clone()),
+            reason: "The room was closed".to_string(),
+        }));
+        self.members.clear();
+        for (_, member) in self.suspended.drain() {
+            ctx.cancel_future(member.expiry);
+        }
+        // the state is saved when stopping
+        ctx.stop();
+    }
+}
+
+impl Handler<SaveRoom> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, _: SaveRoom, _: &mut Context<Self>) {
+        self.save();
     }
 }
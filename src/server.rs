@@ -4,31 +4,117 @@
 
 use crate::{
     greet::welcome_message,
-    messages::{OutgoingMessageDTO, TextMessageDTO},
-    room::{ChatRoom, RoomMessage},
+    messages::{KickDTO, OutgoingMessageDTO, ServerShutdownDTO, SessionDTO, TextMessageDTO},
+    room::{ChatRoom, CloseRoom as CloseChatRoom, RoomMessage, RoomSettings},
 };
 
 use actix::prelude::*;
+use chrono::{DateTime, Utc};
 use rand::{self, rngs::ThreadRng, Rng};
+use serde::Serialize;
 
 use std::collections::HashMap;
+use std::time::Instant;
 
 /// Message for chat server communications
 
 /// New chat session is created
-#[derive(Message)]
-#[rtype(usize)]
 pub struct Connect {
     pub addr: Recipient<RoomMessage>,
+    /// Resume token of a disconnected session to continue
+    pub resume_token: Option<String>,
+    pub remote_addr: Option<String>,
+    pub client_version: Option<String>,
 }
 
-/// Session is disconnected
+pub struct ConnectResult {
+    pub id: usize,
+    /// The state of the continued session, if it was resumed
+    pub resumed: Option<ResumedSession>,
+}
+
+pub struct ResumedSession {
+    pub name: Option<String>,
+    /// The rooms the session is still in, by name
+    pub rooms: Vec<(String, Addr<ChatRoom>)>,
+}
+
+impl actix::Message for Connect {
+    type Result = ConnectResult;
+}
+
+/// Session is disconnected, it can be resumed within the grace period
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Disconnect {
     pub id: usize,
 }
 
+/// The name of a session changed
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Rename {
+    pub id: usize,
+    pub name: String,
+}
+
+/// Metadata of a connected session, for `/sessions`
+#[derive(Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: usize,
+    pub name: Option<String>,
+    pub rooms: Vec<String>,
+    pub connected_at: DateTime<Utc>,
+    pub remote_addr: Option<String>,
+    pub client_version: Option<String>,
+}
+
+impl SessionInfo {
+    /// One line description, i.e. `#3 'Thorin' in Main, GM notes since 2021-06-01 19:00:00 UTC from 10.0.0.2 (0.9)`
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "#{} '{}'",
+            self.id,
+            self.name.as_deref().unwrap_or("<<unknown>>")
+        );
+        if !self.rooms.is_empty() {
+            summary.push_str(&format!(" in {}", self.rooms.join(", ")));
+        }
+        summary.push_str(&format!(
+            " since {}",
+            self.connected_at.format("%Y-%m-%d %H:%M:%S UTC")
+        ));
+        if let Some(remote_addr) = self.remote_addr.as_ref() {
+            summary.push_str(&format!(" from {}", remote_addr));
+        }
+        if let Some(client_version) = self.client_version.as_ref() {
+            summary.push_str(&format!(" ({})", client_version));
+        }
+        summary
+    }
+}
+
+/// A connected session
+struct Session {
+    addr: Recipient<RoomMessage>,
+    /// Token to resume the session after a reconnect
+    token: String,
+    info: SessionInfo,
+}
+
+/// A disconnected session waiting to be resumed
+struct SuspendedSession {
+    info: SessionInfo,
+    since: Instant,
+}
+
+/// Metadata of all connected sessions
+pub struct ListSessions;
+
+impl actix::Message for ListSessions {
+    type Result = Vec<SessionInfo>;
+}
+
 /// List of available rooms
 pub struct ListRooms;
 
@@ -44,24 +130,114 @@ pub struct RequestRoom {
     pub name: String,
 }
 
+/// A session joined a room
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct JoinedRoom {
+    pub id: usize,
+    /// Room name
+    pub name: String,
+}
+
+/// A session left a room or was removed from it
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct LeftRoom {
+    pub id: usize,
+    /// Room name
+    pub name: String,
+}
+
+/// Disconnects a session, it can't be resumed
+pub struct Kick {
+    pub id: usize,
+    pub reason: String,
+}
+
+/// Whether the session was found
+impl actix::Message for Kick {
+    type Result = bool;
+}
+
+/// Removes a room and all of its members, the state of the room is saved
+pub struct CloseRoom {
+    pub name: String,
+}
+
+/// Whether the room was found
+impl actix::Message for CloseRoom {
+    type Result = bool;
+}
+
+/// Sends a system message to all sessions
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Broadcast(pub String);
+
+/// Replaces the text sent to new sessions
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetWelcome(pub String);
+
+/// Tells all sessions that the server goes down, they close their sockets
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Shutdown(pub ServerShutdownDTO);
+
+/// Number of sessions and addresses of all rooms, for `/metrics`
+pub struct GetServerState;
+
+pub struct ServerState {
+    pub sessions: usize,
+    pub rooms: Vec<Addr<ChatRoom>>,
+}
+
+impl actix::Message for GetServerState {
+    type Result = ServerState;
+}
+
+/// Address of an existing room, without creating it
+pub struct FindRoom {
+    pub name: String,
+}
+
+impl actix::Message for FindRoom {
+    type Result = Option<Addr<ChatRoom>>;
+}
+
 /// `ChatServer` manages chat rooms and responsible for coordinating chat
 /// session. implementation is super primitive
 pub struct ChatServer {
-    sessions: HashMap<usize, Recipient<RoomMessage>>,
+    sessions: HashMap<usize, Session>,
     rooms: HashMap<String, Addr<ChatRoom>>,
+    /// Disconnected sessions by resume token
+    suspended: HashMap<String, SuspendedSession>,
+    /// Id of the next session, ids are never reused
+    next_id: usize,
+    /// Sent to new sessions
+    welcome: String,
     rng: ThreadRng,
+    room_settings: RoomSettings,
 }
 
 impl ChatServer {
-    pub fn new() -> ChatServer {
-        // default room
+    pub fn new(default_rooms: &[String], room_settings: RoomSettings) -> ChatServer {
         let mut rooms = HashMap::new();
-        rooms.insert("Main".to_owned(), create_new_room("Main".to_owned()));
+        for name in default_rooms {
+            rooms.insert(
+                name.to_owned(),
+                create_new_room(name.to_owned(), room_settings.clone()),
+            );
+        }
 
         ChatServer {
             sessions: HashMap::new(),
             rooms,
+            suspended: HashMap::new(),
+            next_id: 1,
+            welcome: welcome_message(),
             rng: rand::thread_rng(),
+            room_settings,
         }
     }
 }
@@ -77,39 +253,105 @@ impl Actor for ChatServer {
 ///
 /// Register new session and assign unique id to this session
 impl Handler<Connect> for ChatServer {
-    type Result = usize;
+    type Result = MessageResult<Connect>;
 
     fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
-        debug!("Someone joined");
+        let grace_period = self.room_settings.resume_grace_period;
+        let suspended = msg
+            .resume_token
+            .and_then(|token| self.suspended.remove_entry(&token))
+            .filter(|(_, session)| session.since.elapsed() < grace_period);
+
+        let (token, mut info, resumed) =
+            match suspended {
+                Some((token, session)) => {
+                    debug!("Someone resumed their session");
+                    let resumed = ResumedSession {
+                        name: session.info.name.clone(),
+                        rooms: session
+                            .info
+                            .rooms
+                            .iter()
+                            .filter_map(|room| Some((room.clone(), self.rooms.get(room)?.clone())))
+                            .collect(),
+                    };
+                    (token, session.info, Some(resumed))
+                }
+                None => {
+                    debug!("Someone joined");
+                    let _ = msg.addr.do_send(RoomMessage::from_server(
+                        OutgoingMessageDTO::TextMessage(TextMessageDTO::system(&self.welcome)),
+                    ));
+
+                    // register session with the next id and a random token
+                    let info = SessionInfo {
+                        id: self.next_id,
+                        name: None,
+                        rooms: vec![],
+                        connected_at: Utc::now(),
+                        remote_addr: None,
+                        client_version: None,
+                    };
+                    self.next_id += 1;
+                    let token = format!("{:032x}", self.rng.gen::<u128>());
+                    (token, info, None)
+                }
+            };
+        info.remote_addr = msg.remote_addr;
+        info.client_version = msg.client_version;
+        let id = info.id;
 
         let _ = msg
             .addr
-            .do_send(RoomMessage(OutgoingMessageDTO::TextMessage(
-                TextMessageDTO::system(&welcome_message()),
+            .do_send(RoomMessage::from_server(OutgoingMessageDTO::Session(
+                SessionDTO {
+                    token: token.clone(),
+                    resumed: resumed.is_some(),
+                },
             )));
-
-        // register session with random id
-        let id = self.rng.gen::<usize>();
-        self.sessions.insert(id, msg.addr);
+        self.sessions.insert(
+            id,
+            Session {
+                addr: msg.addr,
+                token,
+                info,
+            },
+        );
 
         // send id back
-        id
+        MessageResult(ConnectResult { id, resumed })
     }
 }
 
-fn create_new_room(name: String) -> Addr<ChatRoom> {
-    ChatRoom::new(name).start()
+fn create_new_room(name: String, settings: RoomSettings) -> Addr<ChatRoom> {
+    ChatRoom::new(name, settings).start()
 }
 
 /// Handler for Disconnect message.
 impl Handler<Disconnect> for ChatServer {
     type Result = ();
 
-    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) {
         debug!("Someone disconnected");
 
-        // remove address
-        self.sessions.remove(&msg.id);
+        // remove address, but keep the session for a while to be resumed
+        if let Some(Session { token, info, .. }) = self.sessions.remove(&msg.id) {
+            self.suspended.insert(
+                token.clone(),
+                SuspendedSession {
+                    info,
+                    since: Instant::now(),
+                },
+            );
+            let grace_period = self.room_settings.resume_grace_period;
+            ctx.run_later(grace_period, move |act, _| {
+                if let Some(session) = act.suspended.get(&token) {
+                    if session.since.elapsed() >= grace_period {
+                        act.suspended.remove(&token);
+                    }
+                }
+            });
+        }
     }
 }
 
@@ -134,10 +376,149 @@ impl Handler<RequestRoom> for ChatServer {
 
     fn handle(&mut self, msg: RequestRoom, _: &mut Context<Self>) -> Self::Result {
         let RequestRoom { name } = msg;
+        let settings = self.room_settings.clone();
 
         self.rooms
             .entry(name.clone())
-            .or_insert(create_new_room(name.to_owned()))
+            .or_insert_with(|| create_new_room(name, settings))
             .clone()
     }
 }
+
+impl Handler<JoinedRoom> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: JoinedRoom, _: &mut Context<Self>) {
+        if let Some(session) = self.sessions.get_mut(&msg.id) {
+            if !session.info.rooms.contains(&msg.name) {
+                session.info.rooms.push(msg.name);
+            }
+        }
+    }
+}
+
+impl Handler<LeftRoom> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: LeftRoom, _: &mut Context<Self>) {
+        if let Some(session) = self.sessions.get_mut(&msg.id) {
+            session.info.rooms.retain(|room| *room != msg.name);
+        }
+    }
+}
+
+impl Handler<Rename> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Rename, _: &mut Context<Self>) {
+        if let Some(session) = self.sessions.get_mut(&msg.id) {
+            session.info.name = Some(msg.name);
+        }
+    }
+}
+
+impl Handler<ListSessions> for ChatServer {
+    type Result = MessageResult<ListSessions>;
+
+    fn handle(&mut self, _: ListSessions, _: &mut Context<Self>) -> Self::Result {
+        let mut sessions: Vec<SessionInfo> =
+            self.sessions.values().map(|s| s.info.clone()).collect();
+        sessions.sort_by_key(|s| s.id);
+        MessageResult(sessions)
+    }
+}
+
+impl Handler<FindRoom> for ChatServer {
+    type Result = Option<Addr<ChatRoom>>;
+
+    fn handle(&mut self, msg: FindRoom, _: &mut Context<Self>) -> Self::Result {
+        self.rooms.get(&msg.name).cloned()
+    }
+}
+
+impl Handler<GetServerState> for ChatServer {
+    type Result = MessageResult<GetServerState>;
+
+    fn handle(&mut self, _: GetServerState, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(ServerState {
+            sessions: self.sessions.len(),
+            rooms: self.rooms.values().cloned().collect(),
+        })
+    }
+}
+
+impl Handler<Kick> for ChatServer {
+    type Result = bool;
+
+    fn handle(&mut self, msg: Kick, _: &mut Context<Self>) -> Self::Result {
+        // the session is removed right away, so it is not suspended on disconnect
+        match self.sessions.remove(&msg.id) {
+            Some(session) => {
+                info!("Kicking session {}: {}", msg.id, msg.reason);
+                let _ = session
+                    .addr
+                    .do_send(RoomMessage::from_server(OutgoingMessageDTO::Kicked(
+                        KickDTO {
+                            room: None,
+                            reason: msg.reason,
+                        },
+                    )));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Handler<CloseRoom> for ChatServer {
+    type Result = bool;
+
+    fn handle(&mut self, msg: CloseRoom, _: &mut Context<Self>) -> Self::Result {
+        match self.rooms.remove(&msg.name) {
+            Some(room) => {
+                info!("Closing room {}", msg.name);
+                room.do_send(CloseChatRoom);
+                for session in self.sessions.values_mut() {
+                    session.info.rooms.retain(|room| *room != msg.name);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Handler<Broadcast> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Broadcast, _: &mut Context<Self>) {
+        for session in self.sessions.values() {
+            let _ =
+                session
+                    .addr
+                    .do_send(RoomMessage::from_server(OutgoingMessageDTO::TextMessage(
+                        TextMessageDTO::system(&msg.0),
+                    )));
+        }
+    }
+}
+
+impl Handler<SetWelcome> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetWelcome, _: &mut Context<Self>) {
+        self.welcome = msg.0;
+    }
+}
+
+impl Handler<Shutdown> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Shutdown, _: &mut Context<Self>) {
+        for session in self.sessions.values() {
+            let _ = session.addr.do_send(RoomMessage::from_server(
+                OutgoingMessageDTO::ServerShutdown(msg.0.clone()),
+            ));
+        }
+    }
+}
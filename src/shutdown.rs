@@ -0,0 +1,73 @@
+//! Graceful shutdown on SIGTERM or Ctrl-C: the clients are told that the
+//! server goes down, the rooms are saved and the sockets are closed before
+//! the actix system stops.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use actix::prelude::*;
+use actix_web::dev::Server;
+use actix_web::rt::signal;
+use futures::future;
+
+use crate::messages::ServerShutdownDTO;
+use crate::room::SaveRoom;
+use crate::server::{ChatServer, GetServerState, Shutdown};
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Relaxed)
+}
+
+/// Waits for SIGTERM or Ctrl-C
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                future::select(Box::pin(signal::ctrl_c()), Box::pin(terminate.recv())).await;
+            }
+            Err(e) => {
+                error!("Could not listen for SIGTERM: {}", e);
+                let _ = signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = signal::ctrl_c().await;
+    }
+}
+
+/// Notifies all sessions, saves all rooms and stops the HTTP servers and the
+/// actix system
+pub async fn shutdown(chat_server: Addr<ChatServer>, http_servers: Vec<Server>, eta: Option<u64>) {
+    SHUTTING_DOWN.store(true, Ordering::Relaxed);
+    info!("Shutting down");
+
+    let notice = ServerShutdownDTO {
+        reason: "The server is shutting down".to_string(),
+        eta,
+    };
+    match chat_server.send(GetServerState).await {
+        Ok(state) => {
+            if let Err(e) = chat_server.send(Shutdown(notice)).await {
+                error!("Could not notify the sessions: {}", e);
+            }
+            for room in state.rooms {
+                if let Err(e) = room.send(SaveRoom).await {
+                    error!("Could not save a room: {}", e);
+                }
+            }
+        }
+        Err(e) => error!("Chat server is not responding: {}", e),
+    }
+
+    for server in http_servers {
+        server.stop(true).await;
+    }
+    System::current().stop();
+}
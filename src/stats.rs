@@ -0,0 +1,130 @@
+//! Running roll statistics of the players in a room, to check whether their
+//! dice are "cursed".
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::messages::{PlayerStatsDTO, TermResultDTO};
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct RoomStats {
+    players: BTreeMap<String, PlayerStats>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct PlayerStats {
+    rolls: u64,
+    /// Rolls with a known expected result
+    compared_rolls: u64,
+    result_sum: i64,
+    expected_sum: f64,
+    explosions: u64,
+    rule_of_ones: u64,
+    /// Die size -> face -> count
+    faces: BTreeMap<u32, BTreeMap<u32, u64>>,
+}
+
+impl RoomStats {
+    pub fn record(&mut self, name: &str, roll: &[TermResultDTO], expected: Option<f64>) {
+        let stats = self.players.entry(name.to_owned()).or_default();
+        stats.rolls += 1;
+
+        if let Some(expected) = expected {
+            stats.compared_rolls += 1;
+            stats.result_sum += roll.iter().map(|t| t.total as i64).sum::<i64>();
+            stats.expected_sum += expected;
+        }
+
+        let dice: Vec<_> = roll.iter().flat_map(|t| t.dice.iter()).collect();
+        // the rule of one: all dice show a one
+        if !dice.is_empty() && dice.iter().all(|d| d.rolls[0] == 1) {
+            stats.rule_of_ones += 1;
+        }
+
+        for die in dice {
+            stats.explosions += die.rolls.len() as u64 - 1;
+            let faces = stats.faces.entry(die.sides).or_default();
+            for face in die.rerolled.iter().chain(die.rolls.iter()) {
+                *faces.entry(*face).or_insert(0) += 1;
+            }
+        }
+    }
+
+    pub fn to_dto(&self) -> Vec<PlayerStatsDTO> {
+        self.players
+            .iter()
+            .map(|(name, stats)| {
+                let (average_result, average_expected) = if stats.compared_rolls > 0 {
+                    (
+                        Some(stats.result_sum as f64 / stats.compared_rolls as f64),
+                        Some(stats.expected_sum / stats.compared_rolls as f64),
+                    )
+                } else {
+                    (None, None)
+                };
+
+                PlayerStatsDTO {
+                    name: name.clone(),
+                    rolls: stats.rolls,
+                    average_result,
+                    average_expected,
+                    explosions: stats.explosions,
+                    rule_of_ones: stats.rule_of_ones,
+                    faces: stats.faces.clone(),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messages::DieResultDTO;
+
+    fn term(total: i32, dice: Vec<DieResultDTO>) -> TermResultDTO {
+        TermResultDTO {
+            expression: String::new(),
+            total,
+            dice,
+        }
+    }
+
+    fn die(rolls: Vec<u32>) -> DieResultDTO {
+        DieResultDTO {
+            sides: 6,
+            rolls,
+            rerolled: None,
+            dropped: false,
+        }
+    }
+
+    #[test]
+    fn test_averages() {
+        let mut stats = RoomStats::default();
+        stats.record("Thorin", &[term(9, vec![die(vec![6, 3])])], Some(4.2));
+        stats.record("Thorin", &[term(1, vec![die(vec![1])])], Some(4.2));
+        stats.record("Thorin", &[term(5, vec![])], None);
+
+        let players = stats.to_dto();
+        assert_eq!(1, players.len());
+        let thorin = &players[0];
+        assert_eq!(3, thorin.rolls);
+        assert_eq!(Some(5.0), thorin.average_result);
+        assert!((thorin.average_expected.unwrap() - 4.2).abs() < 1e-9);
+        assert_eq!(1, thorin.explosions);
+        assert_eq!(1, thorin.rule_of_ones);
+        assert_eq!(1, thorin.faces[&6][&1]);
+        assert_eq!(1, thorin.faces[&6][&6]);
+    }
+
+    #[test]
+    fn test_no_compared_rolls() {
+        let mut stats = RoomStats::default();
+        stats.record("Gimli", &[term(3, vec![die(vec![3])])], None);
+        let gimli = &stats.to_dto()[0];
+        assert_eq!(None, gimli.average_result);
+        assert_eq!(None, gimli.average_expected);
+    }
+}
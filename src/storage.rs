@@ -0,0 +1,115 @@
+//! Optional persistence of room state as one JSON file per room, so it
+//! survives restarts. It is enabled by configuring a `storage_dir`.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::board::Board;
+use crate::history::History;
+use crate::stats::RoomStats;
+
+/// The state of a room that is kept across restarts
+#[derive(Serialize, Deserialize, Default)]
+pub struct PersistedRoom {
+    #[serde(default)]
+    pub stats: RoomStats,
+    #[serde(default)]
+    pub history: History,
+    #[serde(default)]
+    pub board: Board,
+}
+
+pub struct Storage {
+    dir: PathBuf,
+}
+
+impl Storage {
+    pub fn new(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Storage { dir })
+    }
+
+    pub fn load(&self, room: &str) -> Option<PersistedRoom> {
+        let content = match fs::read_to_string(self.path(room)) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                error!("Could not read the state of room '{}': {}", room, e);
+                return None;
+            }
+        };
+
+        match serde_json::from_str(&content) {
+            Ok(state) => Some(state),
+            Err(e) => {
+                error!("Could not parse the state of room '{}': {}", room, e);
+                None
+            }
+        }
+    }
+
+    pub fn save(&self, room: &str, state: &PersistedRoom) -> io::Result<()> {
+        let path = self.path(room);
+        let temporary = path.with_extension("json.tmp");
+        fs::write(&temporary, serde_json::to_vec(state)?)?;
+        fs::rename(temporary, path)
+    }
+
+    /// Room names are user input, so everything but ASCII letters and digits
+    /// is hex encoded to get a safe and unique file name.
+    fn path(&self, room: &str) -> PathBuf {
+        let mut file_name = String::new();
+        for b in room.bytes() {
+            if b.is_ascii_alphanumeric() {
+                file_name.push(b as char);
+            } else {
+                file_name.push_str(&format!("_{:02x}", b));
+            }
+        }
+        file_name.push_str(".json");
+
+        self.dir.join(file_name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messages::TextMessageDTO;
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("eddrc-storage-{}", std::process::id()));
+        let storage = Storage::new(dir.clone()).unwrap();
+        assert!(storage.load("Main").is_none());
+
+        let mut state = PersistedRoom::default();
+        state
+            .history
+            .push(TextMessageDTO::chat("Hello", "Thorin"), None, None);
+        state.board.topic = Some("Into the Kaer".to_string());
+        storage.save("GM notes/1", &state).unwrap();
+
+        let loaded = storage.load("GM notes/1").unwrap();
+        assert_eq!(Some("Into the Kaer".to_string()), loaded.board.topic);
+        assert_eq!("Hello", loaded.history.get(1).unwrap().message());
+        assert!(storage.load("GM notes").is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_names() {
+        let storage = Storage {
+            dir: PathBuf::from("rooms"),
+        };
+        assert_eq!(PathBuf::from("rooms/Main.json"), storage.path("Main"));
+        assert_eq!(
+            PathBuf::from("rooms/GM_20notes_2f_2e_2e.json"),
+            storage.path("GM notes/..")
+        );
+    }
+}
@@ -0,0 +1,97 @@
+//! Optional TLS termination with rustls, plus a plain HTTP listener which
+//! redirects to https.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use actix_web::{http::header, HttpRequest, HttpResponse};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{NoClientAuth, ServerConfig};
+
+/// Creates the rustls configuration from PEM encoded certificate chain and
+/// private key (PKCS#8 or RSA) files
+pub fn load_config(cert_path: &Path, key_path: &Path) -> io::Result<ServerConfig> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    let cert_chain = certs(&mut BufReader::new(File::open(cert_path)?))
+        .map_err(|_| invalid(format!("invalid certificate {}", cert_path.display())))?;
+    if cert_chain.is_empty() {
+        return Err(invalid(format!(
+            "no certificate in {}",
+            cert_path.display()
+        )));
+    }
+
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?))
+        .map_err(|_| invalid(format!("invalid private key {}", key_path.display())))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(File::open(key_path)?))
+            .map_err(|_| invalid(format!("invalid private key {}", key_path.display())))?;
+    }
+    let key = keys
+        .pop()
+        .ok_or_else(|| invalid(format!("no private key in {}", key_path.display())))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(cert_chain, key)
+        .map_err(|e| invalid(e.to_string()))?;
+    Ok(config)
+}
+
+/// Redirects a plain HTTP request to the same path on the https port
+pub fn redirect_to_https(req: &HttpRequest, https_port: u16) -> HttpResponse {
+    let connection = req.connection_info();
+    let host = strip_port(connection.host());
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+
+    let location = if https_port == 443 {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}:{}{}", host, https_port, path)
+    };
+
+    HttpResponse::PermanentRedirect()
+        .header(header::LOCATION, location)
+        .finish()
+}
+
+fn strip_port(host: &str) -> &str {
+    // IPv6 addresses are wrapped in brackets, i.e. `[::1]:8080`
+    match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_redirect() {
+        let req = TestRequest::with_uri("/static/application.html?room=Main")
+            .header(header::HOST, "dice.example.org:8080")
+            .to_http_request();
+        let response = redirect_to_https(&req, 8443);
+        assert_eq!(
+            "https://dice.example.org:8443/static/application.html?room=Main",
+            response.headers().get(header::LOCATION).unwrap()
+        );
+
+        let req = TestRequest::with_uri("/")
+            .header(header::HOST, "[::1]")
+            .to_http_request();
+        let response = redirect_to_https(&req, 443);
+        assert_eq!(
+            "https://[::1]/",
+            response.headers().get(header::LOCATION).unwrap()
+        );
+    }
+}
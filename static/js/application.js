@@ -1,6 +1,8 @@
 const ROOM = "room";
 const NAME = "name";
 const SETTINGS = "settings";
+const INVITE = "invite";
+const CLIENT_VERSION = "0.9";
 const earthdawnStepActionDice = {
     1: '1d4-2',
     2: '1d4-1',
@@ -42,7 +44,9 @@ class myWebsocketHandler {
         const wsUri =
             (window.location.protocol === 'https:' ? 'wss://' : 'ws://') +
             window.location.host +
-            '/ws/'
+            '/ws/?version=' + CLIENT_VERSION +
+            (this.resumeToken ? '&resume=' + encodeURIComponent(this.resumeToken) : '') +
+            this.inviteParameters()
 
         this.socket = new WebSocket(wsUri)
 
@@ -51,38 +55,100 @@ class myWebsocketHandler {
             // main.innerText = ""
             main.classList.remove("disconnected")
             app.connected = true
-            this.autoJoinMessages()
         })
 
         this.socket.addEventListener("message", (event) => {
             console.log(event)
 
             const eventContent = JSON.parse(event.data)
+            if (eventContent.Session)
+                this.handleSession(eventContent.Session)
+
             if (eventContent.TextMessage)
-                this.handleTextMessage(eventContent.TextMessage)
+                this.handleTextMessage(eventContent.TextMessage, false, eventContent.room)
 
             if (eventContent.RoomState)
                 handleRoomStateChange(eventContent.RoomState)
+
+            // combat of the other joined rooms is not shown
+            if (eventContent.CombatState && eventContent.room !== app.room.name)
+                return
+
+            if (eventContent.CombatState)
+                app.combat = eventContent.CombatState
+
+            if (eventContent.Stats)
+                this.handleStats(eventContent.Stats)
+
+            if (eventContent.Export)
+                downloadExport(eventContent.Export)
+
+            if (eventContent.History)
+                this.handleHistory(eventContent.History)
+
+            if (eventContent.ServerShutdown)
+                this.handleServerShutdown(eventContent.ServerShutdown)
+
+            if (eventContent.Kicked)
+                this.handleKicked(eventContent.Kicked)
+
+            if (eventContent.Error)
+                this.handleError(eventContent.Error)
+
+            if (eventContent.MessageEdited)
+                this.handleMessageEdited(eventContent.MessageEdited)
+
+            if (eventContent.MessageDeleted)
+                this.handleMessageDeleted(eventContent.MessageDeleted)
+
+            if (eventContent.Presence)
+                handlePresence(eventContent.Presence, eventContent.room)
+
+            if (eventContent.MemberJoined)
+                handleMemberJoined(eventContent.MemberJoined, eventContent.room)
+
+            if (eventContent.MemberLeft)
+                handleMemberLeft(eventContent.MemberLeft, eventContent.room)
+
+            if (eventContent.MemberRenamed)
+                handleMemberRenamed(eventContent.MemberRenamed, eventContent.room)
         })
 
         this.socket.addEventListener("close", () => {
             document.getElementById("chat-view").classList.add("disconnected")
             app.connected = false
-            this.setupSocket()
+            // a kicked session must not come back on its own
+            if (this.kicked)
+                return
+            const delay = this.reconnectDelay || 0
+            this.reconnectDelay = 0
+            setTimeout(() => this.setupSocket(), delay)
         })
     }
 
-    handleTextMessage(eventContent) {
+    handleTextMessage(eventContent, replayed = false, room = null) {
         const isSystemMessage = eventContent.name == null;
+        // messages of the other joined rooms are marked with the room
+        const roomTag = room != null && room !== app.room.name ? "[" + room + "] " : "";
 
         const pTag = document.createElement("div");
         pTag.className = "chatEntry";
+        if (eventContent.id != null)
+            pTag.dataset.messageId = eventContent.id;
         if (isSystemMessage)
             pTag.className += " systemMessage";
+        if (eventContent.kind === "Emote" || eventContent.kind === "Ooc")
+            pTag.className += " " + eventContent.kind.toLowerCase();
         const namePart = document.createElement("div");
         namePart.className = "name";
+        // an emote reads as a sentence, i.e. "Thorin draws his sword"
         if (!isSystemMessage)
-            namePart.innerHTML = eventContent.name + ":";
+            namePart.innerHTML = roomTag + (eventContent.alias || eventContent.name) + (eventContent.kind === "Emote" ? "" : ":");
+        else
+            namePart.innerHTML = roomTag;
+        // the real sender of a message sent as an alias
+        if (eventContent.alias != null)
+            namePart.title = "sent by " + eventContent.name;
         pTag.append(namePart);
 
         const messagePart = document.createElement("div");
@@ -95,30 +161,144 @@ class myWebsocketHandler {
         message.className = "message";
         messagePart.append(message);
 
-        if (eventContent.dice_results == null) {
+        if (eventContent.bookmark != null) {
+            pTag.className += " bookmark " + eventContent.bookmark.kind.toLowerCase();
+            message.innerText = eventContent.bookmark.kind + ": " + eventContent.bookmark.title;
+        } else if (eventContent.repeated_dice_results != null) {
+            message.innerHTML = eventContent.repeated_dice_results
+                .map(results => results.join(" + ") + " = " + results.reduce((a, b) => a + b, 0))
+                .join("<br>");
+            request.innerHTML = eventContent.message;
+        } else if (eventContent.dice_results == null) {
             message.innerHTML = eventContent.message;
+            if (eventContent.edited)
+                message.innerHTML += " <i>(edited)</i>";
         } else {
             message.innerHTML = eventContent.dice_results.join(" + ") + " = " + eventContent.dice_results.reduce((a, b) => a + b, 0);
             request.innerHTML = eventContent.message;
         }
 
+        if (eventContent.breakdown != null) {
+            const breakdown = document.createElement("div");
+            breakdown.className = "breakdown";
+            breakdown.innerHTML = eventContent.breakdown.map(formatBreakdown).join("<br>");
+            messagePart.append(breakdown);
+        }
+
         const time = document.createElement("div");
         time.className = "time";
         time.innerHTML = this.timeFromTimestamp(eventContent.time);
+        // the id is needed for /edit and /delete
+        if (eventContent.id != null && !isSystemMessage)
+            time.innerHTML = "#" + eventContent.id + " " + time.innerHTML;
         pTag.append(time);
 
         document.getElementById("main").prepend(pTag);
 
+        if (replayed || eventContent.bookmark != null)
+            return
+
         if (isSystemMessage)
             this.updateURLSearchParameters(eventContent.message)
         else
             updateInitiatives(eventContent)
     }
 
+    handleSession(session) {
+        this.resumeToken = session.token
+        // a resumed session is still in its room
+        if (!session.resumed)
+            this.autoJoinMessages()
+    }
+
+    handleError(error) {
+        this.handleTextMessage({
+            message: "!!! " + error.message,
+            name: null,
+            dice_results: null,
+            time: Date.now()
+        }, true)
+    }
+
+    handleMessageEdited(edited) {
+        document.querySelectorAll('#main [data-message-id="' + edited.id + '"] .message')
+            .forEach(message => message.innerHTML = edited.message + " <i>(edited)</i>")
+    }
+
+    handleMessageDeleted(deleted) {
+        document.querySelectorAll('#main [data-message-id="' + deleted.id + '"]')
+            .forEach(entry => entry.remove())
+    }
+
+    handleKicked(kick) {
+        const from = kick.room != null ? "You were removed from " + kick.room : "You were disconnected"
+        this.handleTextMessage({
+            message: from + ": " + kick.reason,
+            name: null,
+            dice_results: null,
+            time: Date.now()
+        }, true)
+        if (kick.room == null)
+            this.kicked = true
+    }
+
+    handleServerShutdown(shutdown) {
+        const eta = shutdown.eta != null ? " It should be back in about " + shutdown.eta + " seconds." : ""
+        this.handleTextMessage({
+            message: shutdown.reason + "." + eta,
+            name: null,
+            dice_results: null,
+            time: Date.now()
+        }, true)
+        // don't hammer the server while it restarts
+        this.reconnectDelay = shutdown.eta != null ? shutdown.eta * 1000 : 5000
+    }
+
+    handleHistory(history) {
+        const session = history.session != null ? " (session " + history.session + ")" : ""
+        this.handleTextMessage({
+            message: "History of " + history.room_name + session + ", " + history.messages.length + " message(s):",
+            name: null,
+            dice_results: null,
+            time: Date.now()
+        }, true)
+        history.messages.forEach(message => this.handleTextMessage(message, true))
+    }
+
+    handleStats(stats) {
+        const lines = stats.players.map(player => {
+            let line = "<b>" + player.name + "</b>: " + player.rolls + " rolls"
+            if (player.average_result != null)
+                line += ", average " + player.average_result.toFixed(1) + " (expected " + player.average_expected.toFixed(1) + ")"
+            line += ", " + player.explosions + " explosions, " + player.rule_of_ones + " rules of one"
+            for (const [sides, faces] of Object.entries(player.faces))
+                line += "<br>d" + sides + ": " + Object.entries(faces).map(([face, count]) => face + "&times;" + count).join(", ")
+            return line
+        })
+
+        this.handleTextMessage({
+            message: "Roll statistics of " + stats.room_name + ":<br>" + (lines.length > 0 ? lines.join("<br>") : "no rolls yet"),
+            name: null,
+            dice_results: null,
+            time: Date.now()
+        })
+    }
+
     submit(message) {
         this.socket.send(message)
     }
 
+    // the invite of an invite link is only tried on the first connect, the
+    // server refuses the connection if it is invalid
+    inviteParameters() {
+        const urlParams = new URLSearchParams(window.location.search)
+        if (this.inviteTried || !urlParams.has(INVITE) || !urlParams.has(ROOM))
+            return ''
+        this.inviteTried = true
+        return '&room=' + encodeURIComponent(urlParams.get(ROOM)) +
+            '&invite=' + encodeURIComponent(urlParams.get(INVITE))
+    }
+
     autoJoinMessages() {
         const urlParams = new URLSearchParams(window.location.search)
 
@@ -135,13 +315,18 @@ class myWebsocketHandler {
 
     updateURLSearchParameters(message) {
         const JOIN_MESSAGE_PREXIFX = "You joined room ";
+        const SWITCH_MESSAGE_PREFIX = "You are now talking in ";
         const NAME_MESSAGE_PREFIX = "You are now known as: "
 
         if (message.startsWith(JOIN_MESSAGE_PREXIFX)) {
             let roomName = message.split(JOIN_MESSAGE_PREXIFX)[1]
             updateURLSearchParameter("room", roomName)
+            showRoom(roomName)
         }
 
+        if (message.startsWith(SWITCH_MESSAGE_PREFIX))
+            showRoom(message.split(SWITCH_MESSAGE_PREFIX)[1])
+
         if (message.startsWith(NAME_MESSAGE_PREFIX)) {
             let userName = message.split(NAME_MESSAGE_PREFIX)[1]
             updateURLSearchParameter("name", userName)
@@ -208,16 +393,28 @@ function updateInitiatives(eventContent) {
     }
 }
 
-const diceLevelRegex = /!{1,2}\[(.*)\].*/
-function expandStepLevel(message) {
-    let matches = message.match(diceLevelRegex)
-    if (matches != null) {
-        let diceLevel = matches[1]
-        let expanded = earthdawnStepActionDice[eval(diceLevel)]
-        return message.replace("[" + diceLevel + "]", expanded)
-    } else {
-        return message
-    }
+function downloadExport(exported) {
+    const link = document.createElement("a")
+    link.href = URL.createObjectURL(new Blob([exported.content], { type: exported.content_type }))
+    link.download = exported.file_name
+    link.click()
+    URL.revokeObjectURL(link.href)
+}
+
+function formatBreakdown(terms) {
+    return terms
+        .filter(term => term.dice.length > 0)
+        .map(term => term.expression + ": " + term.dice.map(formatDie).join(", "))
+        .join(" | ")
+}
+
+function formatDie(die) {
+    let text = die.rolls.join("&rarr;")
+    if (die.rerolled != null)
+        text = "<s>" + die.rerolled + "</s>&#8635;" + text
+    if (die.dropped)
+        text = "<s>" + text + "</s>"
+    return text
 }
 
 function expandHideDice(message) {
@@ -232,10 +429,79 @@ function addKarma(message, karma) {
     return parts.join(' ')
 }
 
+// the state of all joined rooms, only the current room is shown
+const roomStates = {}
+
+// milliseconds between two typing notifications
+const TYPING_INTERVAL = 3000
+let lastTypingSent = 0
+
 function handleRoomStateChange(eventContent) {
+    roomStates[eventContent.room_name] = eventContent
+    if (app.room.name == null || app.room.name === eventContent.room_name)
+        showRoomState(eventContent)
+}
+
+function handlePresence(presence, roomName) {
+    const state = roomStates[roomName]
+    if (state == null)
+        return
+    const member = state.members.find(member => member.id === presence.id)
+    if (member == null)
+        return
+    member.presence = presence.presence
+    if (app.room.name === roomName)
+        showRoomState(state)
+}
+
+// the full member list is only sent on join, afterwards the changes are sent
+function handleMemberJoined(member, roomName) {
+    const state = roomStates[roomName]
+    if (state == null)
+        return
+    state.members = state.members.filter(other => other.id !== member.id)
+    state.members.push(member)
+    if (app.room.name === roomName)
+        showRoomState(state)
+}
+
+function handleMemberLeft(left, roomName) {
+    const state = roomStates[roomName]
+    if (state == null)
+        return
+    // a name can be in the room with several sessions, the id is unique
+    const index = state.members.findIndex(member => member.id === left.id)
+    if (index >= 0)
+        state.members.splice(index, 1)
+    if (app.room.name === roomName)
+        showRoomState(state)
+}
+
+function handleMemberRenamed(renamed, roomName) {
+    const state = roomStates[roomName]
+    if (state == null)
+        return
+    const member = state.members.find(member => member.id === renamed.id)
+    if (member != null)
+        member.name = renamed.new_name
+    if (app.room.name === roomName)
+        showRoomState(state)
+}
+
+function showRoom(roomName) {
+    app.room.name = roomName
+    if (roomStates[roomName] != null)
+        showRoomState(roomStates[roomName])
+}
+
+function showRoomState(eventContent) {
     app.room.name = eventContent.room_name
-    eventContent.members.sort()
-    app.room.members = eventContent.members
+    eventContent.members.sort((a, b) => a.name.localeCompare(b.name))
+    app.room.members = eventContent.members.slice()
+    app.room.effects = eventContent.effects
+    app.room.topic = eventContent.topic
+    app.room.description = eventContent.description
+    app.room.pins = eventContent.pins
 }
 
 var app = new Vue({
@@ -260,10 +526,20 @@ var app = new Vue({
             templates: true
         },
         initiativeRolls: [],
+        combat: {
+            in_combat: false,
+            round: 0,
+            active: null,
+            order: []
+        },
         connected: false,
         room: {
             name: null,
-            members: []
+            members: [],
+            effects: [],
+            topic: null,
+            description: null,
+            pins: []
         },
         games: {
             earthdawn: {
@@ -299,7 +575,6 @@ var app = new Vue({
             if (text == null) {
                 message = this.currentText
                 this.currentText = ""
-                message = expandStepLevel(message)
 
                 if (this.useKarma) {
                     message = addKarma(message, this.games.earthdawn.myKarma)
@@ -316,6 +591,9 @@ var app = new Vue({
 
             websocketClass.submit(message)
         },
+        presenceSymbol: function (presence) {
+            return { Online: "", Idle: " (idle)", Away: " (away)", Typing: " \u270e" }[presence]
+        },
         startDrag: function (evt, index) {
             evt.dataTransfer.dropEffect = 'move'
             evt.dataTransfer.effectAllowed = 'move'
@@ -335,6 +613,16 @@ var app = new Vue({
         endDrag: function(_evt) {
             this.dragging.templates.hoverIndex = null
         }
+    },
+    watch: {
+        // the others see that we are typing, at most every few seconds
+        currentText: function (text) {
+            const now = Date.now()
+            if (text.trim() === "" || !this.connected || now - lastTypingSent < TYPING_INTERVAL)
+                return
+            lastTypingSent = now
+            websocketClass.submit("/typing")
+        }
     }
 })
 